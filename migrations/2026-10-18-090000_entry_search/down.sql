@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER IF EXISTS "users_search_language" ON "users";
+DROP FUNCTION IF EXISTS "users_propagate_search_language"();
+DROP TRIGGER IF EXISTS "entrys_search_language" ON "entrys";
+DROP FUNCTION IF EXISTS "entrys_set_search_language"();
+DROP INDEX IF EXISTS "entrys_search_vector_idx";
+ALTER TABLE "entrys" DROP COLUMN "search_vector";
+ALTER TABLE "entrys" DROP COLUMN "search_language";
+ALTER TABLE "users" DROP COLUMN "search_language";
@@ -0,0 +1,28 @@
+-- Your SQL goes here
+ALTER TABLE "users"
+ADD COLUMN "search_language" TEXT NOT NULL DEFAULT 'simple';
+ALTER TABLE "entrys"
+ADD COLUMN "search_language" REGCONFIG NOT NULL DEFAULT 'simple';
+ALTER TABLE "entrys"
+ADD COLUMN "search_vector" TSVECTOR GENERATED ALWAYS AS (to_tsvector("search_language", coalesce("desc", ''))) STORED;
+CREATE INDEX "entrys_search_vector_idx" ON "entrys" USING GIN ("search_vector");
+
+-- new entries are indexed in the search language of their user
+CREATE FUNCTION "entrys_set_search_language"() RETURNS TRIGGER AS $$
+BEGIN
+	SELECT "search_language"::regconfig INTO NEW."search_language" FROM "users" WHERE "id" = NEW."user_id";
+	RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER "entrys_search_language" BEFORE INSERT ON "entrys"
+FOR EACH ROW EXECUTE FUNCTION "entrys_set_search_language"();
+
+-- changing the search language re-indexes every entry of that user
+CREATE FUNCTION "users_propagate_search_language"() RETURNS TRIGGER AS $$
+BEGIN
+	UPDATE "entrys" SET "search_language" = NEW."search_language"::regconfig WHERE "user_id" = NEW."id";
+	RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER "users_search_language" AFTER UPDATE OF "search_language" ON "users"
+FOR EACH ROW EXECUTE FUNCTION "users_propagate_search_language"();
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "entrys" DROP CONSTRAINT "entrys_fk1";
+ALTER TABLE "entrys"
+ADD CONSTRAINT "entrys_fk1" FOREIGN KEY ("mood_id") REFERENCES "moods"("id") ON DELETE CASCADE;
+ALTER TABLE "moods" DROP COLUMN "archived";
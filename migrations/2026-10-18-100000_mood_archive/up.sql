@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE "moods"
+ADD COLUMN "archived" BOOLEAN NOT NULL DEFAULT FALSE;
+-- deleting a mood must no longer take its entries with it,
+-- NO ACTION (unlike RESTRICT) still lets a user delete cascade through both tables
+ALTER TABLE "entrys" DROP CONSTRAINT "entrys_fk1";
+ALTER TABLE "entrys"
+ADD CONSTRAINT "entrys_fk1" FOREIGN KEY ("mood_id") REFERENCES "moods"("id") ON DELETE NO ACTION;
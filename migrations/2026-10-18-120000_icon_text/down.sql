@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "moods" ALTER COLUMN "icon" TYPE CHARACTER USING left("icon", 1);
+ALTER TABLE "activities" ALTER COLUMN "icon" TYPE CHARACTER USING left("icon", 1);
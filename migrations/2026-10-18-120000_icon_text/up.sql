@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "moods" ALTER COLUMN "icon" TYPE TEXT;
+ALTER TABLE "activities" ALTER COLUMN "icon" TYPE TEXT;
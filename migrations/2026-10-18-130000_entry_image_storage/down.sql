@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "entry_images" DROP COLUMN "content_type";
+ALTER TABLE "entry_images" DROP COLUMN "storage_key";
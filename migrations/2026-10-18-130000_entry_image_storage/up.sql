@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- uploaded images live in the storage backend under "storage_key",
+-- rows without one only link to an image hosted elsewhere
+ALTER TABLE "entry_images"
+ADD COLUMN "storage_key" TEXT UNIQUE;
+ALTER TABLE "entry_images"
+ADD COLUMN "content_type" TEXT;
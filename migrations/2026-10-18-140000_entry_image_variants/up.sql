@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE "entry_image_variants" (
+	"id" SERIAL NOT NULL,
+	"image_id" INT NOT NULL,
+	"name" TEXT NOT NULL,
+	"width" INT NOT NULL,
+	"height" INT NOT NULL,
+	"url" TEXT NOT NULL,
+	"storage_key" TEXT NOT NULL UNIQUE,
+	"content_type" TEXT NOT NULL,
+	CONSTRAINT "entry_image_variants_pk" PRIMARY KEY ("id"),
+	CONSTRAINT "entry_image_variants_name" UNIQUE ("image_id", "name")
+) WITH (OIDS = FALSE);
+ALTER TABLE "entry_image_variants"
+ADD CONSTRAINT "entry_image_variants_fk0" FOREIGN KEY ("image_id") REFERENCES "entry_images"("id") ON DELETE CASCADE;
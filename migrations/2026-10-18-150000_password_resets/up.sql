@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE "password_reset_tokens" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"token_hash" TEXT NOT NULL UNIQUE,
+	"created_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	"expires_at" TIMESTAMP NOT NULL,
+	"used_at" TIMESTAMP,
+	CONSTRAINT "password_reset_tokens_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "password_reset_tokens"
+ADD CONSTRAINT "password_reset_tokens_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
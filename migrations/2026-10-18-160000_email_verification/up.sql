@@ -0,0 +1,14 @@
+-- Your SQL goes here
+ALTER TABLE "users" ADD COLUMN "verified_at" TIMESTAMP;
+-- accounts created before verification existed are trusted as they are
+UPDATE "users" SET "verified_at" = NOW();
+CREATE TABLE "email_verification_tokens" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"token_hash" TEXT NOT NULL UNIQUE,
+	"created_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	"expires_at" TIMESTAMP NOT NULL,
+	CONSTRAINT "email_verification_tokens_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "email_verification_tokens"
+ADD CONSTRAINT "email_verification_tokens_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE "sessions" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"token_hash" TEXT NOT NULL UNIQUE,
+	"user_agent" TEXT,
+	"ip" TEXT,
+	"created_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	"last_seen_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	"expires_at" TIMESTAMP NOT NULL,
+	CONSTRAINT "sessions_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "sessions"
+ADD CONSTRAINT "sessions_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE "api_tokens" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"name" TEXT NOT NULL,
+	"token_hash" TEXT NOT NULL UNIQUE,
+	"scopes" TEXT[] NOT NULL,
+	"created_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	"expires_at" TIMESTAMP,
+	"last_used_at" TIMESTAMP,
+	CONSTRAINT "api_tokens_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "api_tokens"
+ADD CONSTRAINT "api_tokens_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
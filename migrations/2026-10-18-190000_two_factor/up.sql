@@ -0,0 +1,16 @@
+-- Your SQL goes here
+ALTER TABLE "users" ADD COLUMN "totp_secret" TEXT;
+ALTER TABLE "users" ADD COLUMN "totp_enabled_at" TIMESTAMP;
+-- the last time step a code was accepted for, so a code can't be used twice
+ALTER TABLE "users" ADD COLUMN "totp_last_step" BIGINT;
+CREATE TABLE "recovery_codes" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"code_hash" TEXT NOT NULL,
+	"used_at" TIMESTAMP,
+	CONSTRAINT "recovery_codes_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "recovery_codes"
+ADD CONSTRAINT "recovery_codes_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
+-- sessions that passed the password check but still wait for the second factor
+ALTER TABLE "sessions" ADD COLUMN "two_factor_pending" BOOLEAN NOT NULL DEFAULT FALSE;
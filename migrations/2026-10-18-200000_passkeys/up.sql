@@ -0,0 +1,25 @@
+-- Your SQL goes here
+CREATE TABLE "passkeys" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"name" TEXT NOT NULL,
+	"credential_id" TEXT NOT NULL UNIQUE,
+	"passkey" TEXT NOT NULL,
+	"created_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	"last_used_at" TIMESTAMP,
+	CONSTRAINT "passkeys_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "passkeys"
+ADD CONSTRAINT "passkeys_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
+
+CREATE TABLE "passkey_challenges" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"token_hash" TEXT NOT NULL UNIQUE,
+	"kind" TEXT NOT NULL,
+	"state" TEXT NOT NULL,
+	"expires_at" TIMESTAMP NOT NULL,
+	CONSTRAINT "passkey_challenges_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "passkey_challenges"
+ADD CONSTRAINT "passkey_challenges_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE "account_unlock_tokens";
+DROP TABLE "login_attempts";
+ALTER TABLE "users" DROP COLUMN "locked_until";
+ALTER TABLE "users" DROP COLUMN "failed_logins";
@@ -0,0 +1,31 @@
+-- Your SQL goes here
+-- failed password or two-factor checks since the last successful login
+ALTER TABLE "users" ADD COLUMN "failed_logins" INT NOT NULL DEFAULT 0;
+-- set for the backoff between failed attempts as well as for a lockout
+ALTER TABLE "users" ADD COLUMN "locked_until" TIMESTAMP;
+CREATE TABLE "login_attempts" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT,
+	"email" TEXT NOT NULL,
+	"method" TEXT NOT NULL,
+	"outcome" TEXT NOT NULL,
+	"ip" TEXT,
+	"user_agent" TEXT,
+	"created_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	CONSTRAINT "login_attempts_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "login_attempts"
+ADD CONSTRAINT "login_attempts_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
+CREATE INDEX "login_attempts_ip_idx" ON "login_attempts" ("ip", "created_at");
+CREATE INDEX "login_attempts_user_id_idx" ON "login_attempts" ("user_id", "created_at");
+CREATE TABLE "account_unlock_tokens" (
+	"id" SERIAL NOT NULL,
+	"user_id" INT NOT NULL,
+	"token_hash" TEXT NOT NULL UNIQUE,
+	"created_at" TIMESTAMP NOT NULL DEFAULT NOW(),
+	"expires_at" TIMESTAMP NOT NULL,
+	"used_at" TIMESTAMP,
+	CONSTRAINT "account_unlock_tokens_pk" PRIMARY KEY ("id")
+) WITH (OIDS = FALSE);
+ALTER TABLE "account_unlock_tokens"
+ADD CONSTRAINT "account_unlock_tokens_fk0" FOREIGN KEY ("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "entry_activities" DROP CONSTRAINT "entry_activities_entry_id_activity_id_key";
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- an activity is linked to an entry at most once, repeated links are dropped first
+DELETE FROM "entry_activities" AS "duplicate"
+USING "entry_activities" AS "kept"
+WHERE "duplicate"."entry_id" = "kept"."entry_id"
+	AND "duplicate"."activity_id" = "kept"."activity_id"
+	AND "duplicate"."id" > "kept"."id";
+ALTER TABLE "entry_activities"
+ADD CONSTRAINT "entry_activities_entry_id_activity_id_key" UNIQUE ("entry_id", "activity_id");
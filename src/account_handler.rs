@@ -0,0 +1,223 @@
+use std::{
+    io::{Cursor, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use actix_identity::Identity;
+use actix_web::{error::BlockingError, http::header, web, HttpResponse};
+use chrono::Utc;
+use diesel::prelude::*;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use zip::{
+    write::{SimpleFileOptions, ZipWriter},
+    CompressionMethod,
+};
+
+use crate::{
+    auth_handler::{CurrentSession, LoggedUser},
+    entry_handler::{load_big_entries, BigEntry},
+    errors::ServiceError,
+    image_handler::{remove_stored_files, stored_keys},
+    lockout_handler::{check_guarded, AccountCheck},
+    mailer::Mailer,
+    models::{Activity, EnrtyImage, Entry, Mood, Pool, SlimUser, User, ENTRY_COLUMNS},
+    storage::Storage,
+    two_factor_handler::{invalid_code, second_factor_matches},
+    utils::verify,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountData {
+    pub password: String,
+    // only needed with two-factor authentication
+    pub code: Option<String>,
+}
+
+// a stolen session alone must not be enough to delete the account, so the password
+// (and the second factor, if enabled) is asked for again
+pub async fn delete_account(
+    current: CurrentSession,
+    delete_data: web::Json<DeleteAccountData>,
+    id: Identity,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<HttpResponse, ServiceError> {
+    info!("Request to delete the account of {}", current.user.email);
+    let delete_data = delete_data.into_inner();
+    let res =
+        web::block(move || delete_account_query(current, delete_data, pool, storage, mailer)).await;
+
+    match res {
+        Ok(_) => {
+            id.forget();
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn delete_account_query(
+    current: CurrentSession,
+    delete_data: DeleteAccountData,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<(), ServiceError> {
+    use crate::schema::entry_images::dsl::{entry_images, user_id};
+
+    let conn = &pool.get().unwrap();
+    // wrong passwords and codes count against the account like failed logins do
+    let mut wrong_code = false;
+    let user = match check_guarded(current.user.id, &**mailer, conn, |user| {
+        if !verify(&user.hash, &delete_data.password)? {
+            return Ok(false);
+        }
+        if user.totp_enabled_at.is_some() {
+            let code = delete_data.code.as_deref().unwrap_or("");
+            wrong_code = !second_factor_matches(user, code, SystemTime::now(), conn)?;
+        }
+        Ok(!wrong_code)
+    })? {
+        AccountCheck::Passed(user) => user,
+        AccountCheck::Locked(err) => return Err(err),
+        AccountCheck::Failed if wrong_code => return Err(invalid_code()),
+        AccountCheck::Failed => {
+            return Err(ServiceError::Forbidden("Password is incorrect".to_string()))
+        }
+    };
+    let keys = conn.transaction::<_, ServiceError, _>(|| {
+        let image_vec = entry_images
+            .filter(user_id.eq(user.id))
+            .get_results::<EnrtyImage>(conn)?;
+        let keys = stored_keys(&image_vec, conn)?;
+        // everything else the account owns is removed by ON DELETE CASCADE
+        diesel::delete(&user).execute(conn)?;
+        Ok(keys)
+    })?;
+    // the rows are gone at this point, so a file that can't be removed is only logged
+    remove_stored_files(&**storage, keys);
+    info!("Deleted account {}", user.id);
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct AccountExport {
+    #[serde(flatten)]
+    user: SlimUser,
+    search_language: String,
+}
+
+pub async fn export_account(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ServiceError> {
+    info!("Request to export the account of {}", logged_user.email);
+    let res = web::block(move || export_account_query(logged_user, pool, storage)).await;
+
+    match res {
+        Ok(archive) => Ok(HttpResponse::Ok()
+            .content_type("application/zip")
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"moodtracker-export-{}.zip\"",
+                    Utc::now().format("%Y-%m-%d")
+                ),
+            )
+            .body(archive)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn zip_error<E: std::fmt::Display>(err: E) -> ServiceError {
+    error!("Could not write export archive: {}", err);
+    ServiceError::InternalServerError
+}
+
+fn add_json<T: Serialize>(
+    archive: &mut ZipWriter<Cursor<Vec<u8>>>,
+    name: &str,
+    value: &T,
+) -> Result<(), ServiceError> {
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    archive.start_file(name, options).map_err(zip_error)?;
+    serde_json::to_writer_pretty(archive, value).map_err(zip_error)
+}
+
+// account.json, moods.json, activities.json and entries.json, with archived moods and
+// activities included, plus the original of every uploaded image under images/<entry id>/
+fn export_account_query(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<Vec<u8>, ServiceError> {
+    use crate::schema::{
+        activities::dsl::{activities, id as activities_id, user_id as activities_user_id},
+        entrys::dsl::{created_at, entrys, user_id as entrys_user_id},
+        moods::dsl::{id as moods_id, moods, user_id as moods_user_id},
+        users::dsl::users,
+    };
+
+    let conn = &pool.get().unwrap();
+    let user = users.find(logged_user.id).get_result::<User>(conn)?;
+    let mood_vec = moods
+        .filter(moods_user_id.eq(user.id))
+        .order(moods_id)
+        .get_results::<Mood>(conn)?;
+    let activity_vec = activities
+        .filter(activities_user_id.eq(user.id))
+        .order(activities_id)
+        .get_results::<Activity>(conn)?;
+    let entry_vec = entrys
+        .filter(entrys_user_id.eq(user.id))
+        .select(ENTRY_COLUMNS)
+        .order(created_at)
+        .get_results::<Entry>(conn)?;
+    let entry_vec: Vec<BigEntry> = load_big_entries(entry_vec, conn)?;
+
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    add_json(
+        &mut archive,
+        "account.json",
+        &AccountExport {
+            search_language: user.search_language.clone(),
+            user: user.into(),
+        },
+    )?;
+    add_json(&mut archive, "moods.json", &mood_vec)?;
+    add_json(&mut archive, "activities.json", &activity_vec)?;
+    add_json(&mut archive, "entries.json", &entry_vec)?;
+
+    // images are already compressed, so they are only stored
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for image in entry_vec.iter().flat_map(|entry| &entry.images) {
+        // linked images are only referenced by their url in entries.json
+        let key = match image.image.storage_key {
+            Some(ref key) => key,
+            None => continue,
+        };
+        let data = storage.get(key)?;
+        let extension = Path::new(key)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("bin");
+        let name = format!(
+            "images/{}/{}.{}",
+            image.image.entry_id, image.image.id, extension
+        );
+        archive.start_file(name, options).map_err(zip_error)?;
+        archive.write_all(&data).map_err(zip_error)?;
+    }
+
+    Ok(archive.finish().map_err(zip_error)?.into_inner())
+}
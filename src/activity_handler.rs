@@ -4,22 +4,25 @@ use log::info;
 use serde::Deserialize;
 
 use crate::{
-    auth_handler::LoggedUser,
+    auth_handler::{ApiUser, LoggedUser, Scope},
     errors::ServiceError,
-    models::{Activity, NewActivity, Pool, User},
+    icon::deserialize_icon,
+    models::{Activity, ActivityChangeset, NewActivity, Pool, User},
 };
 
 #[derive(Debug, Deserialize)]
 pub struct ActivityData {
     pub name: String,
+    #[serde(deserialize_with = "deserialize_icon")]
     pub icon: String,
 }
 
 pub async fn create_activity(
-    logged_user: LoggedUser,
+    api_user: ApiUser,
     activity_data: web::Json<ActivityData>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ManageMoodsActivities)?;
     info!("Request to create activity by {}", logged_user.email);
     let res =
         web::block(move || create_activity_query(logged_user, activity_data.into_inner(), pool))
@@ -34,7 +37,7 @@ pub async fn create_activity(
     }
 }
 
-fn create_activity_query(
+pub fn create_activity_query(
     logged_user: LoggedUser,
     activity_data: ActivityData,
     pool: web::Data<Pool>,
@@ -50,16 +53,25 @@ fn create_activity_query(
     let inserted_activity = diesel::insert_into(activities)
         .values(&new_activity)
         .get_result(conn)?;
-    dbg!(&&inserted_activity);
     Ok(inserted_activity)
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ActivityQuery {
+    // archived activities are hidden from pickers unless asked for
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
 pub async fn get_activities(
-    logged_user: LoggedUser,
+    api_user: ApiUser,
+    activity_query: web::Query<ActivityQuery>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.any_scope();
     info!("Request to get activities by {}", logged_user.email);
-    let res = web::block(move || get_activities_query(logged_user, pool)).await;
+    let activity_query = activity_query.into_inner();
+    let res = web::block(move || get_activities_query(logged_user, activity_query, pool)).await;
 
     match res {
         Ok(activities) => Ok(HttpResponse::Ok().json(&activities)),
@@ -70,15 +82,175 @@ pub async fn get_activities(
     }
 }
 
-fn get_activities_query(
+pub fn get_activities_query(
     logged_user: LoggedUser,
+    activity_query: ActivityQuery,
     pool: web::Data<Pool>,
 ) -> Result<Vec<Activity>, ServiceError> {
+    use crate::schema::activities::dsl::archived;
     use crate::schema::users::dsl::users;
 
     let conn = &pool.get().unwrap();
     let user = users.find(logged_user.id).get_result::<User>(conn)?;
-    let activities = Activity::belonging_to(&user).get_results(conn)?;
+    let mut query = Activity::belonging_to(&user).into_boxed();
+    if !activity_query.include_archived {
+        query = query.filter(archived.eq(false));
+    }
+    let activities = query.get_results(conn)?;
 
     Ok(activities)
 }
+
+pub async fn update_activity(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    activity_data: web::Json<ActivityChangeset>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ManageMoodsActivities)?;
+    info!("Request to update activity by {}", logged_user.email);
+    let id = id.into_inner();
+    let activity_data = activity_data.into_inner();
+    let res = web::block(move || update_activity_query(id, logged_user, activity_data, pool)).await;
+
+    match res {
+        Ok(activity) => Ok(HttpResponse::Ok().json(&activity)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+pub fn update_activity_query(
+    id: i32,
+    logged_user: LoggedUser,
+    activity_data: ActivityChangeset,
+    pool: web::Data<Pool>,
+) -> Result<Activity, ServiceError> {
+    use crate::schema::activities::dsl::{activities, user_id};
+
+    let conn = &pool.get().unwrap();
+    let activity = activities
+        .find(id)
+        .filter(user_id.eq(logged_user.id))
+        .get_result::<Activity>(conn)?;
+    if activity_data.is_empty() {
+        return Ok(activity);
+    }
+    let updated_activity = diesel::update(&activity)
+        .set(&activity_data)
+        .get_result(conn)?;
+    Ok(updated_activity)
+}
+
+pub async fn delete_activity(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ManageMoodsActivities)?;
+    info!("Request to delete activity by {}", logged_user.email);
+    let id = id.into_inner();
+    let res = web::block(move || delete_activity_query(id, logged_user, pool)).await;
+
+    match res {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+pub fn delete_activity_query(
+    id: i32,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::activities::dsl::{activities, user_id};
+
+    let conn = &pool.get().unwrap();
+    // links to entries are removed by ON DELETE CASCADE, archive to keep them
+    let deleted =
+        diesel::delete(activities.find(id).filter(user_id.eq(logged_user.id))).execute(conn)?;
+    if deleted == 0 {
+        return Err(ServiceError::NotFound);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeData {
+    pub target_id: i32,
+}
+
+pub async fn merge_activity(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    merge_data: web::Json<MergeData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ManageMoodsActivities)?;
+    info!("Request to merge activities by {}", logged_user.email);
+    let id = id.into_inner();
+    let merge_data = merge_data.into_inner();
+    let res = web::block(move || merge_activity_query(id, logged_user, merge_data, pool)).await;
+
+    match res {
+        Ok(activity) => Ok(HttpResponse::Ok().json(&activity)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+// moves every entry link from the source activity onto the target, then drops the source
+fn merge_activity_query(
+    id: i32,
+    logged_user: LoggedUser,
+    merge_data: MergeData,
+    pool: web::Data<Pool>,
+) -> Result<Activity, ServiceError> {
+    use crate::schema::{
+        activities::dsl::{activities, user_id},
+        entry_activities::dsl::{activity_id, entry_activities, entry_id},
+    };
+
+    if id == merge_data.target_id {
+        return Err(ServiceError::BadRequest(
+            "An activity cannot be merged into itself".to_string(),
+        ));
+    }
+
+    let conn = &pool.get().unwrap();
+    conn.transaction(|| {
+        let source = activities
+            .find(id)
+            .filter(user_id.eq(logged_user.id))
+            .get_result::<Activity>(conn)?;
+        let target = activities
+            .find(merge_data.target_id)
+            .filter(user_id.eq(logged_user.id))
+            .get_result::<Activity>(conn)?;
+
+        // entries linked to both would end up with the target twice
+        let already_linked = entry_activities
+            .filter(activity_id.eq(target.id))
+            .select(entry_id)
+            .get_results::<i32>(conn)?;
+        diesel::delete(
+            entry_activities
+                .filter(activity_id.eq(source.id))
+                .filter(entry_id.eq_any(already_linked)),
+        )
+        .execute(conn)?;
+        diesel::update(entry_activities.filter(activity_id.eq(source.id)))
+            .set(activity_id.eq(target.id))
+            .execute(conn)?;
+        diesel::delete(&source).execute(conn)?;
+
+        Ok(target)
+    })
+}
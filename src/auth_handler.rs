@@ -1,15 +1,24 @@
 use actix_identity::Identity;
 use actix_web::{
-    dev::Payload, error::BlockingError, web, Error, FromRequest, HttpRequest, HttpResponse,
+    dev::Payload, error::BlockingError, http::Method, web, Error, FromRequest, HttpRequest,
+    HttpResponse,
 };
 use diesel::{prelude::*, PgConnection, QueryDsl, RunQueryDsl};
-use futures::future::{err, ok, Ready};
-use serde::Deserialize;
+use futures::future::LocalBoxFuture;
+use log::error;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     errors::ServiceError,
-    models::{Pool, SlimUser, User},
-    utils::verify,
+    lockout_handler::{
+        check_guarded, check_ip, record_attempt, register_success, AccountCheck, LoginMethod,
+        LoginOutcome,
+    },
+    mailer::Mailer,
+    models::{Pool, Session, SlimUser, User},
+    session_handler::{create_session, delete_session, touch_session, ClientInfo},
+    token_handler::touch_api_token,
+    utils::{hash_password, needs_rehash, verify},
 };
 
 #[derive(Debug, Deserialize)]
@@ -18,6 +27,81 @@ pub struct AuthData {
     pub password: String,
 }
 
+// what accounts may do before their email address is confirmed, set with UNVERIFIED_ACCESS
+#[derive(Debug, PartialEq)]
+pub enum UnverifiedAccess {
+    // everything, verification is only informational
+    Full,
+    // log in and read, but not change anything
+    ReadOnly,
+    // not even log in
+    None,
+}
+
+lazy_static::lazy_static! {
+    pub static ref UNVERIFIED_ACCESS: UnverifiedAccess =
+        match std::env::var("UNVERIFIED_ACCESS").as_deref() {
+            Ok("full") => UnverifiedAccess::Full,
+            Ok("none") => UnverifiedAccess::None,
+            Ok("read_only") | Err(_) => UnverifiedAccess::ReadOnly,
+            Ok(other) => panic!("UNVERIFIED_ACCESS must be full, read_only or none, not {}", other),
+        };
+}
+
+fn unverified_error() -> ServiceError {
+    ServiceError::Forbidden("Please confirm your email address first".to_string())
+}
+
+// for every way of logging in, accounts may be kept out until they are verified
+pub fn check_may_log_in(user: &User) -> Result<(), ServiceError> {
+    if user.verified_at.is_none() && *UNVERIFIED_ACCESS == UnverifiedAccess::None {
+        return Err(unverified_error());
+    }
+    Ok(())
+}
+
+fn check_verified(user: &User, read_only: bool) -> Result<(), ServiceError> {
+    let allowed = user.verified_at.is_some()
+        || match *UNVERIFIED_ACCESS {
+            UnverifiedAccess::Full => true,
+            UnverifiedAccess::ReadOnly => read_only,
+            UnverifiedAccess::None => false,
+        };
+    if !allowed {
+        return Err(unverified_error());
+    }
+    Ok(())
+}
+
+// the auth cookie only carries a session token, the session itself lives in `sessions`
+// so it can be listed and revoked
+fn authenticate(
+    req: &HttpRequest,
+    pl: &mut Payload,
+) -> LocalBoxFuture<'static, Result<(Session, User), Error>> {
+    let token = Identity::from_request(req, pl)
+        .into_inner()
+        .ok()
+        .and_then(|identity| identity.identity());
+    let pool = req.app_data::<web::Data<Pool>>().cloned();
+    let read_only = matches!(*req.method(), Method::GET | Method::HEAD);
+
+    Box::pin(async move {
+        let (token, pool) = match (token, pool) {
+            (Some(token), Some(pool)) => (token, pool),
+            _ => return Err(ServiceError::Unauthorized.into()),
+        };
+        let res = web::block(move || touch_session(&token, &pool.get().unwrap())).await;
+        let (session, user) = match res {
+            Ok(found) => found,
+            Err(BlockingError::Error(service_error)) => return Err(service_error.into()),
+            Err(BlockingError::Canceled) => return Err(ServiceError::InternalServerError.into()),
+        };
+        check_verified(&user, read_only)?;
+        Ok((session, user))
+    })
+}
+
 // we need the same data
 // simple aliasing makes the intentions clear and its more readable
 pub type LoggedUser = SlimUser;
@@ -25,36 +109,179 @@ pub type LoggedUser = SlimUser;
 impl FromRequest for LoggedUser {
     type Config = ();
     type Error = Error;
-    type Future = Ready<Result<LoggedUser, Error>>;
+    type Future = LocalBoxFuture<'static, Result<LoggedUser, Error>>;
 
     fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
-        if let Ok(identity) = Identity::from_request(req, pl).into_inner() {
-            if let Some(user_json) = identity.identity() {
-                if let Ok(user) = serde_json::from_str(&user_json) {
-                    return ok(user);
-                }
-            }
+        let session = authenticate(req, pl);
+        Box::pin(async move { session.await.map(|(_, user)| user.into()) })
+    }
+}
+
+// for handlers that need to know which session made the request
+pub struct CurrentSession {
+    pub session: Session,
+    pub user: LoggedUser,
+}
+
+impl FromRequest for CurrentSession {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<CurrentSession, Error>>;
+
+    fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
+        let session = authenticate(req, pl);
+        Box::pin(async move {
+            session.await.map(|(session, user)| CurrentSession {
+                session,
+                user: user.into(),
+            })
+        })
+    }
+}
+
+// what a personal access token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    ReadEntries,
+    WriteEntries,
+    ManageMoodsActivities,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [
+        Scope::ReadEntries,
+        Scope::WriteEntries,
+        Scope::ManageMoodsActivities,
+    ];
+
+    // the name stored in `api_tokens.scopes`, same as in JSON
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadEntries => "read_entries",
+            Scope::WriteEntries => "write_entries",
+            Scope::ManageMoodsActivities => "manage_moods_activities",
         }
-        err(ServiceError::Unauthorized.into())
     }
 }
 
-pub async fn logout(id: Identity) -> HttpResponse {
+// for endpoints scripts may use: accepts an `Authorization: Bearer` personal access token
+// as well as the auth cookie, sessions from the cookie are granted every scope
+pub struct ApiUser {
+    user: LoggedUser,
+    // None for cookie sessions
+    scopes: Option<Vec<String>>,
+}
+
+impl ApiUser {
+    pub fn require(self, scope: Scope) -> Result<LoggedUser, ServiceError> {
+        self.check(scope)?;
+        Ok(self.user)
+    }
+
+    // the same without giving up the ApiUser, for requests that need several scopes
+    pub fn check(&self, scope: Scope) -> Result<&LoggedUser, ServiceError> {
+        match self.scopes {
+            Some(ref scopes) if !scopes.iter().any(|granted| granted == scope.as_str()) => Err(
+                ServiceError::Forbidden(format!("Token lacks the {} scope", scope.as_str())),
+            ),
+            _ => Ok(&self.user),
+        }
+    }
+
+    // for reads any token may do, like listing the moods an entry can use
+    pub fn any_scope(self) -> LoggedUser {
+        self.user
+    }
+
+    pub fn user(&self) -> &LoggedUser {
+        &self.user
+    }
+}
+
+impl FromRequest for ApiUser {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ApiUser, Error>>;
+
+    fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
+        let bearer = req
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let token = match bearer {
+            Some(token) => token,
+            None => {
+                let session = authenticate(req, pl);
+                return Box::pin(async move {
+                    session.await.map(|(_, user)| ApiUser {
+                        user: user.into(),
+                        scopes: None,
+                    })
+                });
+            }
+        };
+        let pool = req.app_data::<web::Data<Pool>>().cloned();
+        let read_only = matches!(*req.method(), Method::GET | Method::HEAD);
+
+        Box::pin(async move {
+            let pool = pool.ok_or(ServiceError::InternalServerError)?;
+            let res = web::block(move || touch_api_token(&token, &pool.get().unwrap())).await;
+            let (api_token, user) = match res {
+                Ok(found) => found,
+                Err(BlockingError::Error(service_error)) => return Err(service_error.into()),
+                Err(BlockingError::Canceled) => {
+                    return Err(ServiceError::InternalServerError.into())
+                }
+            };
+            check_verified(&user, read_only)?;
+            Ok(ApiUser {
+                user: user.into(),
+                scopes: Some(api_token.scopes),
+            })
+        })
+    }
+}
+
+pub async fn logout(id: Identity, pool: web::Data<Pool>) -> Result<HttpResponse, ServiceError> {
+    if let Some(token) = id.identity() {
+        let res = web::block(move || delete_session(&token, &pool.get().unwrap())).await;
+        if let Err(err) = res {
+            return match err {
+                BlockingError::Error(service_error) => Err(service_error),
+                BlockingError::Canceled => Err(ServiceError::InternalServerError),
+            };
+        }
+    }
     id.forget();
-    HttpResponse::Ok().finish()
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Serialize)]
+struct TwoFactorRequired {
+    two_factor_required: bool,
 }
 
 pub async fn login(
+    req: HttpRequest,
     auth_data: web::Json<AuthData>,
     id: Identity,
     pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
 ) -> Result<HttpResponse, ServiceError> {
-    let result = web::block(move || query(auth_data.into_inner(), pool)).await;
+    let client = ClientInfo::from_request(&req);
+    let result = web::block(move || query(auth_data.into_inner(), client, pool, mailer)).await;
     match result {
-        Ok(user) => {
-            let user_json = serde_json::to_string(&user).unwrap();
-
-            id.remember(user_json);
+        Ok((user, token, two_factor_pending)) => {
+            id.remember(token);
+            if two_factor_pending {
+                // the client has to follow up with POST /api/auth/2fa
+                return Ok(HttpResponse::Accepted().json(TwoFactorRequired {
+                    two_factor_required: true,
+                }));
+            }
             Ok(HttpResponse::Ok().json(user))
         }
         Err(err) => match err {
@@ -69,19 +296,85 @@ pub async fn get_me(logged_user: LoggedUser) -> HttpResponse {
 }
 
 // diesel query
-fn query(auth_data: AuthData, pool: web::Data<Pool>) -> Result<SlimUser, ServiceError> {
-    use crate::schema::users::dsl::{email, users};
+fn query(
+    auth_data: AuthData,
+    client: ClientInfo,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<(SlimUser, String, bool), ServiceError> {
+    use crate::schema::users::dsl::{email, hash, users};
 
     let conn: &PgConnection = &pool.get().unwrap();
-    let mut items = users
+    let user = users
         .filter(email.eq(&auth_data.email))
-        .load::<User>(conn)?;
-    if let Some(user) = items.pop() {
-        if let Ok(matching) = verify(&user.hash, &auth_data.password) {
-            if matching {
-                return Ok(user.into());
-            }
+        .get_result::<User>(conn)
+        .optional()?;
+    // every attempt ends up in the login history, whatever the outcome
+    let user_id = user.as_ref().map(|user| user.id);
+    let record = |outcome: LoginOutcome| {
+        record_attempt(
+            user_id,
+            &auth_data.email,
+            LoginMethod::Password,
+            outcome,
+            &client,
+            conn,
+        )
+    };
+
+    if let Err(err) = check_ip(&client, conn) {
+        record(LoginOutcome::Throttled)?;
+        return Err(err);
+    }
+    let user = match user {
+        Some(user) => user,
+        None => {
+            record(LoginOutcome::UnknownAccount)?;
+            return Err(ServiceError::Unauthorized);
+        }
+    };
+    // the password isn't even checked while the account is backing off, a locked account
+    // gets the same answer as an unknown address so the lockout can't tell which ones exist
+    let user = match check_guarded(user.id, &**mailer, conn, |user| {
+        verify(&user.hash, &auth_data.password)
+    })? {
+        AccountCheck::Passed(user) => user,
+        AccountCheck::Locked(_) => {
+            record(LoginOutcome::Locked)?;
+            return Err(ServiceError::Unauthorized);
         }
+        AccountCheck::Failed => {
+            record(LoginOutcome::WrongPassword)?;
+            return Err(ServiceError::Unauthorized);
+        }
+    };
+    if let Err(err) = check_may_log_in(&user) {
+        record(LoginOutcome::Unverified)?;
+        return Err(err);
+    }
+    // the plain password is only known here, so older hashes are upgraded on login
+    if needs_rehash(&user.hash) {
+        let upgraded = hash_password(&auth_data.password).and_then(|new_hash| {
+            diesel::update(&user)
+                .set(hash.eq(new_hash))
+                .execute(conn)
+                .map_err(ServiceError::from)
+        });
+        if let Err(err) = upgraded {
+            error!(
+                "Could not upgrade password hash of user {}: {}",
+                user.id, err
+            );
+        }
+    }
+    // with two-factor authentication the failures are only reset once the code was right
+    let two_factor_pending = user.totp_enabled_at.is_some();
+    if two_factor_pending {
+        record(LoginOutcome::TwoFactorRequired)?;
+    } else {
+        record(LoginOutcome::Success)?;
+        register_success(&user, conn)?;
     }
-    Err(ServiceError::Unauthorized)
+    let token = create_session(user.id, &client, two_factor_pending, conn)?;
+    Ok((user.into(), token, two_factor_pending))
 }
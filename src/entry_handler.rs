@@ -1,11 +1,23 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use actix_web::{error::BlockingError, web, HttpResponse};
-use diesel::prelude::*;
+use chrono::NaiveDate;
+use diesel::{pg::Pg, prelude::*};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    auth_handler::LoggedUser,
+    auth_handler::{ApiUser, LoggedUser, Scope},
     errors::ServiceError,
-    models::{Activity, Entry, EntryActivity, Mood, NewEntry, NewEntryActivity, Pool, User},
+    image_handler::{load_big_images, remove_stored_files, stored_keys, BigImage},
+    models::{
+        Activity, EnrtyImage, Entry, EntryActivity, EntryChangeset, Mood, NewEntry,
+        NewEntryActivity, NewEntryImage, Pool, User, ENTRY_COLUMNS,
+    },
+    storage::Storage,
+    utils::deserialize_some,
 };
 
 #[derive(Debug, Serialize)]
@@ -16,16 +28,85 @@ pub struct BigEntry {
     pub desc: Option<String>,
     pub created_at: std::time::SystemTime,
     pub activities: Vec<Activity>,
+    pub images: Vec<BigImage>,
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct EntryQuery {
+    // both bounds are inclusive calendar days
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    // id lists are comma separated, e.g. `mood_ids=1,2`
+    pub mood_ids: Option<String>,
+    pub min_value: Option<i32>,
+    pub max_value: Option<i32>,
+    pub all_activities: Option<String>,
+    pub any_activities: Option<String>,
+    pub without_activities: Option<String>,
+    pub has_desc: Option<bool>,
+}
+
+fn parse_ids(ids: &str) -> Result<Vec<i32>, ServiceError> {
+    ids.split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(|part| {
+            part.trim()
+                .parse::<i32>()
+                .map_err(|_| ServiceError::BadRequest(format!("Invalid id: {}", part)))
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryPage {
+    pub entries: Vec<BigEntry>,
+    pub next_cursor: Option<String>,
+}
+
+// position of the last entry on a page, clients must treat the encoded form as opaque
+struct EntryCursor {
+    created_at: SystemTime,
+    id: i32,
+}
+
+impl EntryCursor {
+    fn encode(&self) -> String {
+        let micros = self
+            .created_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        format!("{}_{}", micros, self.id)
+    }
+
+    fn decode(cursor: &str) -> Result<Self, ServiceError> {
+        let invalid = || ServiceError::BadRequest("Invalid cursor".to_string());
+        let (micros, id) = cursor.split_once('_').ok_or_else(invalid)?;
+        let micros = micros.parse::<u64>().map_err(|_| invalid())?;
+        let id = id.parse::<i32>().map_err(|_| invalid())?;
+        Ok(EntryCursor {
+            created_at: UNIX_EPOCH + Duration::from_micros(micros),
+            id,
+        })
+    }
 }
 
 pub async fn get_entrys(
-    logged_user: LoggedUser,
+    api_user: ApiUser,
+    entry_query: web::Query<EntryQuery>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
-    let res = web::block(move || get_entrys_query(logged_user, pool)).await;
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    let entry_query = entry_query.into_inner();
+    let res = web::block(move || get_entrys_query(logged_user, entry_query, pool)).await;
 
     match res {
-        Ok(entrys) => Ok(HttpResponse::Ok().json(&entrys)),
+        Ok(page) => Ok(HttpResponse::Ok().json(&page)),
         Err(err) => match err {
             BlockingError::Error(service_error) => Err(service_error),
             BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -33,46 +114,114 @@ pub async fn get_entrys(
     }
 }
 
-fn get_entrys_query(
+pub fn get_entrys_query(
     logged_user: LoggedUser,
+    entry_query: EntryQuery,
     pool: web::Data<Pool>,
-) -> Result<Vec<BigEntry>, ServiceError> {
+) -> Result<EntryPage, ServiceError> {
     use crate::schema::{
-        activities::dsl::{activities, id},
         entry_activities::dsl::{activity_id, entry_activities, entry_id},
-        entrys::dsl::{created_at, entrys, user_id},
-        moods::dsl::moods,
+        entrys::dsl::{created_at, desc, entrys, id, mood_id, user_id},
+        moods::dsl::{moods, value},
         users::dsl::users,
     };
 
     let conn = &pool.get().unwrap();
     let user: User = users.find(logged_user.id).get_result::<User>(conn)?;
-    let entry_vec = entrys
+    let limit = entry_query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // every filter below ends up in a single statement over entrys, moods and entry_activities
+    let mut query = entrys
+        .inner_join(moods)
+        .select(ENTRY_COLUMNS)
         .filter(user_id.eq(user.id))
-        .order(created_at.desc())
-        .get_results::<Entry>(conn)?;
+        .into_boxed();
+    if let Some(mood_ids) = entry_query.mood_ids {
+        query = query.filter(mood_id.eq_any(parse_ids(&mood_ids)?));
+    }
+    if let Some(min_value) = entry_query.min_value {
+        query = query.filter(value.ge(min_value));
+    }
+    if let Some(max_value) = entry_query.max_value {
+        query = query.filter(value.le(max_value));
+    }
+    if let Some(all_activities) = entry_query.all_activities {
+        for required in parse_ids(&all_activities)? {
+            query = query.filter(
+                id.eq_any(
+                    entry_activities
+                        .select(entry_id)
+                        .filter(activity_id.eq(required)),
+                ),
+            );
+        }
+    }
+    if let Some(any_activities) = entry_query.any_activities {
+        query = query.filter(
+            id.eq_any(
+                entry_activities
+                    .select(entry_id)
+                    .filter(activity_id.eq_any(parse_ids(&any_activities)?)),
+            ),
+        );
+    }
+    if let Some(without_activities) = entry_query.without_activities {
+        query = query.filter(diesel::dsl::not(
+            id.eq_any(
+                entry_activities
+                    .select(entry_id)
+                    .filter(activity_id.eq_any(parse_ids(&without_activities)?)),
+            ),
+        ));
+    }
+    match entry_query.has_desc {
+        Some(true) => query = query.filter(desc.is_not_null().and(desc.ne(""))),
+        Some(false) => query = query.filter(desc.is_null().or(desc.eq(""))),
+        None => {}
+    }
+    if let Some(from) = entry_query.from {
+        query = query.filter(created_at.ge(from.and_hms(0, 0, 0)));
+    }
+    if let Some(to) = entry_query.to {
+        let end = to
+            .succ_opt()
+            .ok_or_else(|| ServiceError::BadRequest("Invalid date range".to_string()))?;
+        query = query.filter(created_at.lt(end.and_hms(0, 0, 0)));
+    }
+    if let Some(cursor) = entry_query.cursor {
+        let cursor = EntryCursor::decode(&cursor)?;
+        query = query.filter(
+            created_at
+                .lt(cursor.created_at)
+                .or(created_at.eq(cursor.created_at).and(id.lt(cursor.id))),
+        );
+    }
 
-    let mut res = Vec::new();
-    for entry in entry_vec {
-        let mood = moods.find(entry.mood_id).get_result::<Mood>(conn)?;
-        let activity_ids = entry_activities
-            .filter(entry_id.eq(entry.id))
-            .select(activity_id)
-            .load::<i32>(conn)?;
-        let activity_vec = activities
-            .filter(id.eq_any(activity_ids))
-            .get_results::<Activity>(conn)?;
-        res.push(BigEntry {
-            id: entry.id,
-            user_id: entry.user_id,
-            mood,
-            desc: entry.desc,
-            created_at: entry.created_at,
-            activities: activity_vec,
+    // one extra row tells us whether another page follows
+    let mut entry_vec = query
+        .order((created_at.desc(), id.desc()))
+        .limit(limit + 1)
+        .get_results::<Entry>(conn)?;
+    let next_cursor = if entry_vec.len() as i64 > limit {
+        entry_vec.truncate(limit as usize);
+        entry_vec.last().map(|entry| {
+            EntryCursor {
+                created_at: entry.created_at,
+                id: entry.id,
+            }
+            .encode()
         })
-    }
-    dbg!(&res);
-    Ok(res)
+    } else {
+        None
+    };
+
+    Ok(EntryPage {
+        entries: load_big_entries(entry_vec, conn)?,
+        next_cursor,
+    })
 }
 
 #[derive(Debug, Deserialize)]
@@ -81,13 +230,16 @@ pub struct EntryData {
     pub desc: Option<String>,
     pub created_at: Option<std::time::SystemTime>,
     pub activity_ids: Vec<i32>,
+    #[serde(default)]
+    pub image_urls: Vec<String>,
 }
 
 pub async fn create_entry(
-    logged_user: LoggedUser,
+    api_user: ApiUser,
     entry_data: web::Json<EntryData>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::WriteEntries)?;
     let entry_data = entry_data.into_inner();
     let res = web::block(move || create_entry_query(logged_user, entry_data, pool)).await;
 
@@ -100,54 +252,133 @@ pub async fn create_entry(
     }
 }
 
-fn create_entry_query(
+pub fn create_entry_query(
     logged_user: LoggedUser,
-    entry_data: EntryData,
+    mut entry_data: EntryData,
     pool: web::Data<Pool>,
-) -> Result<(Entry, Vec<EntryActivity>), ServiceError> {
-    use crate::schema::{entry_activities::dsl::entry_activities, entrys::dsl::entrys};
+) -> Result<BigEntry, ServiceError> {
+    use crate::schema::entrys::dsl::entrys;
 
+    entry_data.activity_ids = unique_ids(entry_data.activity_ids);
     let conn = &pool.get().unwrap();
-    let mut new_entry = NewEntry {
-        user_id: logged_user.id,
-        mood_id: entry_data.mood_id,
-        desc: None,
-        created_at: None,
-    };
+    // nothing is kept unless the entry, its activities and its images all make it in
+    conn.transaction(|| {
+        check_mood_owner(entry_data.mood_id, logged_user.id, conn)?;
+        check_activity_owner(&entry_data.activity_ids, logged_user.id, conn)?;
 
-    if let Some(desc) = entry_data.desc {
-        new_entry.desc = Some(desc);
-    }
-    if let Some(time) = entry_data.created_at {
-        new_entry.created_at = Some(time);
-    }
+        let new_entry = NewEntry {
+            user_id: logged_user.id,
+            mood_id: entry_data.mood_id,
+            desc: entry_data.desc,
+            created_at: entry_data.created_at,
+        };
+        let inserted_entry = diesel::insert_into(entrys)
+            .values(new_entry)
+            .returning(ENTRY_COLUMNS)
+            .get_result::<Entry>(conn)?;
+        insert_entry_activities(&inserted_entry, entry_data.activity_ids, conn)?;
+        insert_entry_images(&inserted_entry, &entry_data.image_urls, conn)?;
 
-    let inserted_entry = diesel::insert_into(entrys)
-        .values(new_entry)
-        .get_result::<Entry>(conn)?;
-    dbg!(&inserted_entry);
-    let mut activity_vec: Vec<NewEntryActivity> = Vec::new();
+        load_big_entry(inserted_entry, conn)
+    })
+}
+
+// an activity is linked to an entry once, however often the request lists it
+fn unique_ids(mut ids: Vec<i32>) -> Vec<i32> {
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
 
-    for activity_id in entry_data.activity_ids {
-        activity_vec.push(NewEntryActivity {
-            entry_id: inserted_entry.id,
+fn insert_entry_activities(
+    entry: &Entry,
+    activity_ids: Vec<i32>,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::entry_activities::dsl::entry_activities;
+
+    let activity_vec: Vec<NewEntryActivity> = activity_ids
+        .into_iter()
+        .map(|activity_id| NewEntryActivity {
+            entry_id: entry.id,
             activity_id,
         })
+        .collect();
+    diesel::insert_into(entry_activities)
+        .values(activity_vec)
+        .execute(conn)?;
+    Ok(())
+}
+
+fn insert_entry_images(
+    entry: &Entry,
+    image_urls: &[String],
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::entry_images::dsl::entry_images;
+
+    let image_vec: Vec<NewEntryImage> = image_urls
+        .iter()
+        .map(|image_url| NewEntryImage {
+            user_id: entry.user_id,
+            entry_id: entry.id,
+            image_url,
+            storage_key: None,
+            content_type: None,
+        })
+        .collect();
+    diesel::insert_into(entry_images)
+        .values(image_vec)
+        .execute(conn)?;
+    Ok(())
+}
+
+// an entry may only reference the moods and activities of its own user
+fn check_mood_owner(mood_id: i32, owner_id: i32, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::moods::dsl::{moods, user_id};
+
+    let owned = moods
+        .find(mood_id)
+        .filter(user_id.eq(owner_id))
+        .count()
+        .get_result::<i64>(conn)?;
+    if owned == 0 {
+        return Err(ServiceError::Forbidden(format!(
+            "Mood {} does not belong to you",
+            mood_id
+        )));
     }
+    Ok(())
+}
 
-    let inserted_activities = diesel::insert_into(entry_activities)
-        .values(activity_vec)
-        .get_results(conn)?;
-    dbg!(&inserted_activities);
-    Ok((inserted_entry, inserted_activities))
+fn check_activity_owner(
+    activity_ids: &[i32],
+    owner_id: i32,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::activities::dsl::{activities, id, user_id};
+
+    let owned = activities
+        .filter(id.eq_any(activity_ids))
+        .filter(user_id.eq(owner_id))
+        .select(id)
+        .get_results::<i32>(conn)?;
+    if let Some(foreign) = activity_ids.iter().find(|a| !owned.contains(a)) {
+        return Err(ServiceError::Forbidden(format!(
+            "Activity {} does not belong to you",
+            foreign
+        )));
+    }
+    Ok(())
 }
 
 pub async fn get_entry_by_id(
-    logged_user: LoggedUser,
-    id: web::Path<String>,
+    api_user: ApiUser,
+    id: web::Path<i32>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
-    let id = id.into_inner().parse::<i32>().unwrap();
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    let id = id.into_inner();
     let res = web::block(move || get_entry_by_id_query(id, logged_user, pool)).await;
 
     match res {
@@ -159,36 +390,380 @@ pub async fn get_entry_by_id(
     }
 }
 
-fn get_entry_by_id_query(
+pub fn get_entry_by_id_query(
     id: i32,
     logged_user: LoggedUser,
     pool: web::Data<Pool>,
 ) -> Result<BigEntry, ServiceError> {
-    use crate::schema::{
-        activities::dsl::{activities, id as activities_id},
-        entry_activities::dsl::{activity_id, entry_activities, entry_id},
-        entrys::dsl::{entrys, user_id},
-        moods::dsl::moods,
-    };
+    use crate::schema::entrys::dsl::{entrys, user_id};
+
     let conn = &pool.get().unwrap();
     let entry = entrys
         .find(id)
         .filter(user_id.eq(logged_user.id))
+        .select(ENTRY_COLUMNS)
         .get_result::<Entry>(conn)?;
-    let mood = moods.find(entry.mood_id).get_result::<Mood>(conn)?;
-    let activity_ids = entry_activities
-        .filter(entry_id.eq(entry.id))
-        .select(activity_id)
-        .get_results::<i32>(conn)?;
-    let activity_vec = activities
-        .filter(activities_id.eq_any(activity_ids))
-        .get_results::<Activity>(conn)?;
-    Ok(BigEntry {
-        id,
-        user_id: logged_user.id,
-        mood,
-        desc: entry.desc,
-        created_at: entry.created_at,
-        activities: activity_vec,
+    load_big_entry(entry, conn)
+}
+
+fn load_big_entry(entry: Entry, conn: &PgConnection) -> Result<BigEntry, ServiceError> {
+    load_big_entries(vec![entry], conn)?
+        .pop()
+        .ok_or(ServiceError::NotFound)
+}
+
+// moods, activities, images and their variants are fetched with one query each,
+// so the query count stays the same no matter how many entries are loaded; any
+// Postgres connection will do, which lets the tests count the queries
+pub fn load_big_entries<C: Connection<Backend = Pg>>(
+    entry_vec: Vec<Entry>,
+    conn: &C,
+) -> Result<Vec<BigEntry>, ServiceError> {
+    use crate::schema::{
+        activities::dsl::activities,
+        entry_activities::dsl::id as entry_activities_id,
+        entry_images::dsl::id as entry_images_id,
+        moods::dsl::{id as moods_id, moods},
+    };
+
+    let mood_ids: Vec<i32> = entry_vec.iter().map(|entry| entry.mood_id).collect();
+    let mood_map: HashMap<i32, Mood> = moods
+        .filter(moods_id.eq_any(mood_ids))
+        .get_results::<Mood>(conn)?
+        .into_iter()
+        .map(|mood| (mood.id, mood))
+        .collect();
+    let activity_groups = EntryActivity::belonging_to(&entry_vec)
+        .inner_join(activities)
+        .order(entry_activities_id)
+        .get_results::<(EntryActivity, Activity)>(conn)?
+        .grouped_by(&entry_vec);
+    let image_vec = EnrtyImage::belonging_to(&entry_vec)
+        .order(entry_images_id)
+        .get_results::<EnrtyImage>(conn)?;
+    let mut image_map: HashMap<i32, Vec<BigImage>> = HashMap::new();
+    for image in load_big_images(image_vec, conn)? {
+        image_map
+            .entry(image.image.entry_id)
+            .or_default()
+            .push(image);
+    }
+
+    entry_vec
+        .into_iter()
+        .zip(activity_groups)
+        .map(|(entry, activity_pairs)| {
+            let mood = mood_map
+                .get(&entry.mood_id)
+                .cloned()
+                .ok_or(ServiceError::NotFound)?;
+            Ok(BigEntry {
+                id: entry.id,
+                user_id: entry.user_id,
+                mood,
+                desc: entry.desc,
+                created_at: entry.created_at,
+                activities: activity_pairs
+                    .into_iter()
+                    .map(|(_, activity)| activity)
+                    .collect(),
+                images: image_map.remove(&entry.id).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+// every field is optional, only the ones present in the request are changed
+#[derive(Debug, Deserialize)]
+pub struct EntryPatchData {
+    pub mood_id: Option<i32>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub desc: Option<Option<String>>,
+    pub created_at: Option<std::time::SystemTime>,
+    pub activity_ids: Option<Vec<i32>>,
+    pub image_urls: Option<Vec<String>>,
+}
+
+// a PUT replaces everything but the creation time, which is kept when left out;
+// a missing description clears it
+impl From<EntryData> for EntryPatchData {
+    fn from(entry_data: EntryData) -> Self {
+        EntryPatchData {
+            mood_id: Some(entry_data.mood_id),
+            desc: Some(entry_data.desc),
+            created_at: entry_data.created_at,
+            activity_ids: Some(entry_data.activity_ids),
+            image_urls: Some(entry_data.image_urls),
+        }
+    }
+}
+
+pub async fn update_entry(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    entry_data: web::Json<EntryData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::WriteEntries)?;
+    let id = id.into_inner();
+    let patch = entry_data.into_inner().into();
+    let res = web::block(move || update_entry_query(id, logged_user, patch, pool)).await;
+
+    match res {
+        Ok(entry) => Ok(HttpResponse::Ok().json(&entry)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+pub async fn patch_entry(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    entry_data: web::Json<EntryPatchData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::WriteEntries)?;
+    let id = id.into_inner();
+    let patch = entry_data.into_inner();
+    let res = web::block(move || update_entry_query(id, logged_user, patch, pool)).await;
+
+    match res {
+        Ok(entry) => Ok(HttpResponse::Ok().json(&entry)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+pub fn update_entry_query(
+    id: i32,
+    logged_user: LoggedUser,
+    mut patch: EntryPatchData,
+    pool: web::Data<Pool>,
+) -> Result<BigEntry, ServiceError> {
+    use crate::schema::entrys::dsl::{entrys, user_id};
+
+    patch.activity_ids = patch.activity_ids.map(unique_ids);
+    let conn = &pool.get().unwrap();
+    conn.transaction(|| {
+        let mut entry = entrys
+            .find(id)
+            .filter(user_id.eq(logged_user.id))
+            .select(ENTRY_COLUMNS)
+            .get_result::<Entry>(conn)?;
+
+        if let Some(mood_id) = patch.mood_id {
+            check_mood_owner(mood_id, logged_user.id, conn)?;
+        }
+        if let Some(activity_ids) = &patch.activity_ids {
+            check_activity_owner(activity_ids, logged_user.id, conn)?;
+        }
+
+        let changeset = EntryChangeset {
+            mood_id: patch.mood_id,
+            desc: patch.desc,
+            created_at: patch.created_at,
+        };
+        if !changeset.is_empty() {
+            entry = diesel::update(&entry)
+                .set(&changeset)
+                .returning(ENTRY_COLUMNS)
+                .get_result::<Entry>(conn)?;
+        }
+
+        if let Some(activity_ids) = patch.activity_ids {
+            diesel::delete(EntryActivity::belonging_to(&entry)).execute(conn)?;
+            insert_entry_activities(&entry, activity_ids, conn)?;
+        }
+        if let Some(image_urls) = patch.image_urls {
+            use crate::schema::entry_images::dsl::storage_key;
+
+            // uploaded images are managed through /entry/{id}/images
+            diesel::delete(EnrtyImage::belonging_to(&entry).filter(storage_key.is_null()))
+                .execute(conn)?;
+            insert_entry_images(&entry, &image_urls, conn)?;
+        }
+
+        load_big_entry(entry, conn)
     })
 }
+
+pub async fn delete_entry(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::WriteEntries)?;
+    let id = id.into_inner();
+    let res = web::block(move || delete_entry_query(id, logged_user, pool, storage)).await;
+
+    match res {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+pub fn delete_entry_query(
+    id: i32,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<(), ServiceError> {
+    use crate::schema::{
+        entry_images::dsl::{entry_id, entry_images},
+        entrys::dsl::{entrys, user_id},
+    };
+
+    let conn = &pool.get().unwrap();
+    let keys = conn.transaction(|| {
+        let image_vec = entry_images
+            .filter(entry_id.eq(id))
+            .get_results::<EnrtyImage>(conn)?;
+        let keys = stored_keys(&image_vec, conn)?;
+        // entry_activities, entry_images and their variants are removed by ON DELETE CASCADE
+        let deleted =
+            diesel::delete(entrys.find(id).filter(user_id.eq(logged_user.id))).execute(conn)?;
+        if deleted == 0 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(keys)
+    })?;
+    remove_stored_files(&**storage, keys);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::NewEntryImageVariant,
+        test_utils::{counting_connection, create_activity, create_mood, pool, TestUser},
+    };
+
+    // an entry with an activity and an image with a variant, so every query runs
+    fn create_entry(user_id: i32, mood_id: i32, activity_id: i32, conn: &PgConnection) -> Entry {
+        use crate::schema::{
+            entry_activities::dsl::entry_activities,
+            entry_image_variants::dsl::entry_image_variants, entry_images::dsl::entry_images,
+            entrys::dsl::entrys,
+        };
+
+        let entry = diesel::insert_into(entrys)
+            .values(NewEntry {
+                user_id,
+                mood_id,
+                desc: None,
+                created_at: None,
+            })
+            .returning(ENTRY_COLUMNS)
+            .get_result::<Entry>(conn)
+            .unwrap();
+        diesel::insert_into(entry_activities)
+            .values(NewEntryActivity {
+                entry_id: entry.id,
+                activity_id,
+            })
+            .execute(conn)
+            .unwrap();
+        let image = diesel::insert_into(entry_images)
+            .values(NewEntryImage {
+                user_id,
+                entry_id: entry.id,
+                image_url: "/images/test.png",
+                storage_key: None,
+                content_type: None,
+            })
+            .get_result::<EnrtyImage>(conn)
+            .unwrap();
+        diesel::insert_into(entry_image_variants)
+            .values(NewEntryImageVariant {
+                image_id: image.id,
+                name: "thumbnail",
+                width: 1,
+                height: 1,
+                url: "/images/test-thumbnail.png",
+                storage_key: &format!("test-{}-thumbnail.png", image.id),
+                content_type: "image/png",
+            })
+            .execute(conn)
+            .unwrap();
+        entry
+    }
+
+    fn create_mood_and_activity(user_id: i32, conn: &PgConnection) -> (Mood, Activity) {
+        (
+            create_mood(user_id, 3, conn),
+            create_activity(user_id, "Reading", conn),
+        )
+    }
+
+    #[test]
+    fn big_entries_load_with_a_constant_query_count() {
+        let pool = pool();
+        let test_user = TestUser::create(&pool);
+        let user_id = test_user.user.id;
+        let conn = &pool.get().unwrap();
+        let (mood, activity) = create_mood_and_activity(user_id, conn);
+        let single_vec = vec![create_entry(user_id, mood.id, activity.id, conn)];
+        let entry_vec: Vec<Entry> = (0..5)
+            .map(|_| create_entry(user_id, mood.id, activity.id, conn))
+            .collect();
+
+        let counting_conn = counting_connection();
+        let single = load_big_entries(single_vec, &counting_conn).unwrap();
+        let single_queries = counting_conn.queries();
+        let all = load_big_entries(entry_vec, &counting_conn).unwrap();
+        let all_queries = counting_conn.queries() - single_queries;
+
+        assert_eq!(single.len(), 1);
+        assert_eq!(all.len(), 5);
+        assert!(all
+            .iter()
+            .all(|entry| entry.activities.len() == 1 && entry.images[0].variants.len() == 1));
+        assert_eq!(single_queries, all_queries);
+    }
+
+    #[test]
+    fn repeated_activity_ids_are_linked_once() {
+        let pool = web::Data::new(pool());
+        let test_user = TestUser::create(&pool);
+        let conn = &pool.get().unwrap();
+        let (mood, activity) = create_mood_and_activity(test_user.user.id, conn);
+
+        let entry = create_entry_query(
+            test_user.logged_user(),
+            EntryData {
+                mood_id: mood.id,
+                desc: None,
+                created_at: None,
+                activity_ids: vec![activity.id, activity.id],
+                image_urls: Vec::new(),
+            },
+            pool.clone(),
+        )
+        .unwrap();
+        assert_eq!(entry.activities.len(), 1);
+
+        let entry = update_entry_query(
+            entry.id,
+            test_user.logged_user(),
+            EntryPatchData {
+                mood_id: None,
+                desc: None,
+                created_at: None,
+                activity_ids: Some(vec![activity.id, activity.id, activity.id]),
+                image_urls: None,
+            },
+            pool.clone(),
+        )
+        .unwrap();
+        assert_eq!(entry.activities.len(), 1);
+    }
+}
@@ -1,5 +1,5 @@
 // errors.rs
-use actix_web::{error::ResponseError, HttpResponse};
+use actix_web::{error::ResponseError, http::header, HttpResponse};
 use derive_more::Display;
 use diesel::result::{DatabaseErrorKind, Error as DBError};
 use std::convert::From;
@@ -14,6 +14,19 @@ pub enum ServiceError {
 
     #[display(fmt = "Unauthorized")]
     Unauthorized,
+
+    #[display(fmt = "Not Found")]
+    NotFound,
+
+    #[display(fmt = "Forbidden: {}", _0)]
+    Forbidden(String),
+
+    #[display(fmt = "Conflict: {}", _0)]
+    Conflict(String),
+
+    // the number of seconds the client should wait before trying again
+    #[display(fmt = "Too Many Requests, retry after {} seconds", _0)]
+    TooManyRequests(u64),
 }
 
 // impl ResponseError trait allows to convert our errors into http responses with appropriate data
@@ -25,6 +38,12 @@ impl ResponseError for ServiceError {
             }
             ServiceError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
             ServiceError::Unauthorized => HttpResponse::Unauthorized().json("Unauthorized"),
+            ServiceError::NotFound => HttpResponse::NotFound().json("Not Found"),
+            ServiceError::Forbidden(ref message) => HttpResponse::Forbidden().json(message),
+            ServiceError::Conflict(ref message) => HttpResponse::Conflict().json(message),
+            ServiceError::TooManyRequests(retry_after) => HttpResponse::TooManyRequests()
+                .header(header::RETRY_AFTER, retry_after.to_string())
+                .json("Too many attempts, please try again later"),
         }
     }
 }
@@ -41,6 +60,7 @@ impl From<DBError> for ServiceError {
                 }
                 ServiceError::InternalServerError
             }
+            DBError::NotFound => ServiceError::NotFound,
             _ => ServiceError::InternalServerError,
         }
     }
@@ -0,0 +1,688 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::{DateTime, NaiveDate, Utc};
+use juniper::{
+    graphql_object, graphql_value,
+    http::{graphiql::graphiql_source, GraphQLBatchRequest, GraphQLRequest},
+    parser::parse_document_source,
+    Definition, EmptySubscription, FieldError, GraphQLInputObject, IntoFieldError, RootNode,
+    ScalarValue, Selection,
+};
+use log::{debug, error};
+use serde::Deserialize;
+
+use crate::{
+    activity_handler::{
+        create_activity_query, delete_activity_query, get_activities_query, update_activity_query,
+        ActivityData, ActivityQuery,
+    },
+    auth_handler::{ApiUser, LoggedUser, Scope},
+    entry_handler::{
+        create_entry_query, delete_entry_query, get_entry_by_id_query, get_entrys_query,
+        update_entry_query, BigEntry, EntryData, EntryPage, EntryPatchData, EntryQuery,
+    },
+    errors::ServiceError,
+    icon::validate_icon,
+    image_handler::BigImage,
+    models::{Activity, ActivityChangeset, EntryImageVariant, Mood, MoodChangeset, Pool, SlimUser},
+    mood_handler::{
+        create_mood_query, delete_mood_query, get_moods_query, update_mood_query, DeleteMoodQuery,
+        MoodData, MoodQuery,
+    },
+    storage::Storage,
+};
+
+// every operation of a batch runs one after the other on the same blocking thread
+const MAX_BATCH_SIZE: usize = 10;
+// counted in nested fields, enough for the schema and the introspection query of GraphiQL
+const MAX_QUERY_DEPTH: usize = 15;
+
+// the resolvers call the same queries as the REST handlers, with the same scopes
+pub struct Context {
+    api_user: ApiUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    fn require(&self, scope: Scope) -> Result<LoggedUser, ServiceError> {
+        self.api_user.check(scope).cloned()
+    }
+
+    fn any_scope(&self) -> LoggedUser {
+        self.api_user.user().clone()
+    }
+}
+
+// the message is what the REST endpoints would answer, the code tells the cases apart
+impl<S: ScalarValue> IntoFieldError<S> for ServiceError {
+    fn into_field_error(self) -> FieldError<S> {
+        let (code, message) = match self {
+            ServiceError::InternalServerError => (
+                "INTERNAL_SERVER_ERROR",
+                "Internal Server Error, Please try later".to_string(),
+            ),
+            ServiceError::BadRequest(message) => ("BAD_REQUEST", message),
+            ServiceError::Unauthorized => ("UNAUTHORIZED", "Unauthorized".to_string()),
+            ServiceError::NotFound => ("NOT_FOUND", "Not Found".to_string()),
+            ServiceError::Forbidden(message) => ("FORBIDDEN", message),
+            ServiceError::Conflict(message) => ("CONFLICT", message),
+            ServiceError::TooManyRequests(_) => (
+                "TOO_MANY_REQUESTS",
+                "Too many attempts, please try again later".to_string(),
+            ),
+        };
+        FieldError::new(message, graphql_value!({ "code": code }))
+    }
+}
+
+#[graphql_object(context = Context, name = "User")]
+impl SlimUser {
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn email(&self) -> &str {
+        &self.email
+    }
+
+    fn verified(&self) -> bool {
+        self.verified
+    }
+
+    fn two_factor_enabled(&self) -> bool {
+        self.two_factor_enabled
+    }
+}
+
+#[graphql_object(context = Context)]
+impl Mood {
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn value(&self) -> i32 {
+        self.value
+    }
+
+    fn icon(&self) -> &str {
+        &self.icon
+    }
+
+    fn archived(&self) -> bool {
+        self.archived
+    }
+}
+
+#[graphql_object(context = Context)]
+impl Activity {
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn icon(&self) -> &str {
+        &self.icon
+    }
+
+    fn archived(&self) -> bool {
+        self.archived
+    }
+}
+
+// entries come out of load_big_entries with everything below already loaded,
+// so nested selections never query per entry
+#[graphql_object(context = Context, name = "Entry")]
+impl BigEntry {
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn mood(&self) -> &Mood {
+        &self.mood
+    }
+
+    fn desc(&self) -> Option<&str> {
+        self.desc.as_deref()
+    }
+
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at.into()
+    }
+
+    fn activities(&self) -> &[Activity] {
+        &self.activities
+    }
+
+    fn images(&self) -> &[BigImage] {
+        &self.images
+    }
+}
+
+#[graphql_object(context = Context, name = "EntryImage")]
+impl BigImage {
+    fn id(&self) -> i32 {
+        self.image.id
+    }
+
+    fn url(&self) -> &str {
+        &self.image.image_url
+    }
+
+    // null for linked images
+    fn content_type(&self) -> Option<&str> {
+        self.image.content_type.as_deref()
+    }
+
+    fn variants(&self) -> &[EntryImageVariant] {
+        &self.variants
+    }
+}
+
+#[graphql_object(context = Context, name = "EntryImageVariant")]
+impl EntryImageVariant {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn width(&self) -> i32 {
+        self.width
+    }
+
+    fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+}
+
+#[graphql_object(context = Context)]
+impl EntryPage {
+    fn entries(&self) -> &[BigEntry] {
+        &self.entries
+    }
+
+    // pass as `after` to get the next page, null on the last one
+    fn next_cursor(&self) -> Option<&str> {
+        self.next_cursor.as_deref()
+    }
+}
+
+// the same filters as GET /api/entry
+#[derive(Debug, Default, GraphQLInputObject)]
+pub struct EntryFilter {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub mood_ids: Option<Vec<i32>>,
+    pub min_value: Option<i32>,
+    pub max_value: Option<i32>,
+    pub all_activities: Option<Vec<i32>>,
+    pub any_activities: Option<Vec<i32>>,
+    pub without_activities: Option<Vec<i32>>,
+    pub has_desc: Option<bool>,
+}
+
+// the REST query takes its id lists comma separated
+fn join_ids(ids: Option<Vec<i32>>) -> Option<String> {
+    ids.map(|ids| ids.iter().map(i32::to_string).collect::<Vec<_>>().join(","))
+}
+
+pub struct Query;
+
+#[graphql_object(context = Context)]
+impl Query {
+    fn me(context: &Context) -> SlimUser {
+        context.any_scope()
+    }
+
+    fn moods(context: &Context, include_archived: Option<bool>) -> Result<Vec<Mood>, ServiceError> {
+        let mood_query = MoodQuery {
+            include_archived: include_archived.unwrap_or(false),
+        };
+        get_moods_query(context.any_scope(), mood_query, context.pool.clone())
+    }
+
+    fn activities(
+        context: &Context,
+        include_archived: Option<bool>,
+    ) -> Result<Vec<Activity>, ServiceError> {
+        let activity_query = ActivityQuery {
+            include_archived: include_archived.unwrap_or(false),
+        };
+        get_activities_query(context.any_scope(), activity_query, context.pool.clone())
+    }
+
+    // newest first, `first` entries per page starting after the `after` cursor
+    fn entries(
+        context: &Context,
+        filter: Option<EntryFilter>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<EntryPage, ServiceError> {
+        let logged_user = context.require(Scope::ReadEntries)?;
+        let filter = filter.unwrap_or_default();
+        let entry_query = EntryQuery {
+            from: filter.from,
+            to: filter.to,
+            limit: first.map(i64::from),
+            cursor: after,
+            mood_ids: join_ids(filter.mood_ids),
+            min_value: filter.min_value,
+            max_value: filter.max_value,
+            all_activities: join_ids(filter.all_activities),
+            any_activities: join_ids(filter.any_activities),
+            without_activities: join_ids(filter.without_activities),
+            has_desc: filter.has_desc,
+        };
+        get_entrys_query(logged_user, entry_query, context.pool.clone())
+    }
+
+    fn entry(context: &Context, id: i32) -> Result<BigEntry, ServiceError> {
+        let logged_user = context.require(Scope::ReadEntries)?;
+        get_entry_by_id_query(id, logged_user, context.pool.clone())
+    }
+}
+
+#[derive(Debug, GraphQLInputObject)]
+pub struct MoodInput {
+    pub name: String,
+    // a single emoji or `<set>:<name>`
+    pub icon: String,
+    pub value: i32,
+}
+
+#[derive(Debug, GraphQLInputObject)]
+pub struct MoodPatch {
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub value: Option<i32>,
+    pub archived: Option<bool>,
+}
+
+#[derive(Debug, GraphQLInputObject)]
+pub struct ActivityInput {
+    pub name: String,
+    pub icon: String,
+}
+
+#[derive(Debug, GraphQLInputObject)]
+pub struct ActivityPatch {
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub archived: Option<bool>,
+}
+
+#[derive(Debug, GraphQLInputObject)]
+pub struct EntryInput {
+    pub mood_id: i32,
+    pub desc: Option<String>,
+    // defaults to now
+    pub created_at: Option<DateTime<Utc>>,
+    pub activity_ids: Option<Vec<i32>>,
+    pub image_urls: Option<Vec<String>>,
+}
+
+// only the fields that are set are changed, an empty desc clears it
+#[derive(Debug, GraphQLInputObject)]
+pub struct EntryPatch {
+    pub mood_id: Option<i32>,
+    pub desc: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub activity_ids: Option<Vec<i32>>,
+    pub image_urls: Option<Vec<String>>,
+}
+
+pub struct Mutation;
+
+// deletions return the id of what was deleted
+#[graphql_object(context = Context)]
+impl Mutation {
+    fn create_mood(context: &Context, input: MoodInput) -> Result<Mood, ServiceError> {
+        let logged_user = context.require(Scope::ManageMoodsActivities)?;
+        let mood_data = MoodData {
+            name: input.name,
+            icon: validate_icon(input.icon)?,
+            value: input.value,
+        };
+        create_mood_query(logged_user, mood_data, context.pool.clone())
+    }
+
+    fn update_mood(context: &Context, id: i32, input: MoodPatch) -> Result<Mood, ServiceError> {
+        let logged_user = context.require(Scope::ManageMoodsActivities)?;
+        let changeset = MoodChangeset {
+            name: input.name,
+            value: input.value,
+            icon: input.icon.map(validate_icon).transpose()?,
+            archived: input.archived,
+        };
+        update_mood_query(id, logged_user, changeset, context.pool.clone())
+    }
+
+    // a mood still used by entries can only be deleted if they are moved to `reassign_to`
+    fn delete_mood(
+        context: &Context,
+        id: i32,
+        reassign_to: Option<i32>,
+    ) -> Result<i32, ServiceError> {
+        let logged_user = context.require(Scope::ManageMoodsActivities)?;
+        let delete_query = DeleteMoodQuery { reassign_to };
+        delete_mood_query(id, logged_user, delete_query, context.pool.clone())?;
+        Ok(id)
+    }
+
+    fn create_activity(context: &Context, input: ActivityInput) -> Result<Activity, ServiceError> {
+        let logged_user = context.require(Scope::ManageMoodsActivities)?;
+        let activity_data = ActivityData {
+            name: input.name,
+            icon: validate_icon(input.icon)?,
+        };
+        create_activity_query(logged_user, activity_data, context.pool.clone())
+    }
+
+    fn update_activity(
+        context: &Context,
+        id: i32,
+        input: ActivityPatch,
+    ) -> Result<Activity, ServiceError> {
+        let logged_user = context.require(Scope::ManageMoodsActivities)?;
+        let changeset = ActivityChangeset {
+            name: input.name,
+            icon: input.icon.map(validate_icon).transpose()?,
+            archived: input.archived,
+        };
+        update_activity_query(id, logged_user, changeset, context.pool.clone())
+    }
+
+    fn delete_activity(context: &Context, id: i32) -> Result<i32, ServiceError> {
+        let logged_user = context.require(Scope::ManageMoodsActivities)?;
+        delete_activity_query(id, logged_user, context.pool.clone())?;
+        Ok(id)
+    }
+
+    fn create_entry(context: &Context, input: EntryInput) -> Result<BigEntry, ServiceError> {
+        let logged_user = context.require(Scope::WriteEntries)?;
+        let entry_data = EntryData {
+            mood_id: input.mood_id,
+            desc: input.desc,
+            created_at: input.created_at.map(SystemTime::from),
+            activity_ids: input.activity_ids.unwrap_or_default(),
+            image_urls: input.image_urls.unwrap_or_default(),
+        };
+        create_entry_query(logged_user, entry_data, context.pool.clone())
+    }
+
+    fn update_entry(
+        context: &Context,
+        id: i32,
+        input: EntryPatch,
+    ) -> Result<BigEntry, ServiceError> {
+        let logged_user = context.require(Scope::WriteEntries)?;
+        let patch = EntryPatchData {
+            mood_id: input.mood_id,
+            desc: input
+                .desc
+                .map(|desc| if desc.is_empty() { None } else { Some(desc) }),
+            created_at: input.created_at.map(SystemTime::from),
+            activity_ids: input.activity_ids,
+            image_urls: input.image_urls,
+        };
+        update_entry_query(id, logged_user, patch, context.pool.clone())
+    }
+
+    fn delete_entry(context: &Context, id: i32) -> Result<i32, ServiceError> {
+        let logged_user = context.require(Scope::WriteEntries)?;
+        delete_entry_query(
+            id,
+            logged_user,
+            context.pool.clone(),
+            context.storage.clone(),
+        )?;
+        Ok(id)
+    }
+}
+
+pub type Schema = RootNode<'static, Query, Mutation, EmptySubscription<Context>>;
+
+pub fn create_schema() -> Schema {
+    Schema::new(Query, Mutation, EmptySubscription::new())
+}
+
+pub async fn graphql(
+    api_user: ApiUser,
+    request: web::Json<GraphQLBatchRequest>,
+    schema: web::Data<Schema>,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ServiceError> {
+    debug!("GraphQL request by user {}", api_user.user().id);
+    let request = request.into_inner();
+    check_limits(&request, &schema)?;
+    let context = Context {
+        api_user,
+        pool,
+        storage,
+    };
+    let res = web::block(move || {
+        let response = request.execute_sync(&schema, &context);
+        let body = serde_json::to_string(&response).map_err(|err| {
+            error!("Could not serialize GraphQL response: {}", err);
+            ServiceError::InternalServerError
+        })?;
+        Ok::<_, ServiceError>((response.is_ok(), body))
+    })
+    .await;
+
+    match res {
+        // errors in single fields still come with the data of the others
+        Ok((true, body)) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(body)),
+        // the request itself was invalid, e.g. a syntax error or an unknown field
+        Ok((false, body)) => Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(body)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn check_limits(request: &GraphQLBatchRequest, schema: &Schema) -> Result<(), ServiceError> {
+    let request_vec = match request {
+        GraphQLBatchRequest::Single(request) => vec![request],
+        GraphQLBatchRequest::Batch(request_vec) => {
+            if request_vec.len() > MAX_BATCH_SIZE {
+                return Err(ServiceError::BadRequest(format!(
+                    "At most {} operations may be sent at once",
+                    MAX_BATCH_SIZE
+                )));
+            }
+            request_vec.iter().collect()
+        }
+    };
+    for request in request_vec {
+        if query_depth(&query_text(request)?, schema) > MAX_QUERY_DEPTH {
+            return Err(ServiceError::BadRequest(format!(
+                "Queries may nest at most {} fields deep",
+                MAX_QUERY_DEPTH
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct QueryText {
+    query: String,
+}
+
+// juniper keeps the query of a request to itself, so it is read back from its JSON form
+fn query_text(request: &GraphQLRequest) -> Result<String, ServiceError> {
+    serde_json::to_value(request)
+        .and_then(serde_json::from_value::<QueryText>)
+        .map(|text| text.query)
+        .map_err(|err| {
+            error!("Could not read GraphQL query: {}", err);
+            ServiceError::InternalServerError
+        })
+}
+
+// how deep the fields of the deepest operation nest, with fragments spread into place;
+// a query that doesn't parse counts as flat and is rejected by juniper with its error
+fn query_depth(query: &str, schema: &Schema) -> usize {
+    let document = match parse_document_source(query, &schema.schema) {
+        Ok(document) => document,
+        Err(_) => return 0,
+    };
+    let mut fragments = HashMap::new();
+    for definition in &document {
+        if let Definition::Fragment(fragment) = definition {
+            fragments.insert(fragment.item.name.item, &fragment.item.selection_set[..]);
+        }
+    }
+    let mut fragment_depths = HashMap::new();
+    document
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation(operation) => Some(selection_depth(
+                &operation.item.selection_set,
+                &fragments,
+                &mut fragment_depths,
+            )),
+            Definition::Fragment(_) => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// each fragment is measured once, so spreading them into each other can't blow up the work;
+// one that spreads itself counts as flat, juniper rejects those cycles anyway
+fn selection_depth<'a>(
+    selection_set: &[Selection<'a>],
+    fragments: &HashMap<&'a str, &[Selection<'a>]>,
+    fragment_depths: &mut HashMap<&'a str, usize>,
+) -> usize {
+    selection_set
+        .iter()
+        .map(|selection| match selection {
+            Selection::Field(field) => match &field.item.selection_set {
+                Some(selection_set) => {
+                    1 + selection_depth(selection_set, fragments, fragment_depths)
+                }
+                None => 1,
+            },
+            Selection::FragmentSpread(spread) => {
+                let name = spread.item.name.item;
+                if let Some(depth) = fragment_depths.get(name) {
+                    return *depth;
+                }
+                fragment_depths.insert(name, 0);
+                let depth = fragments.get(name).map_or(0, |selection_set| {
+                    selection_depth(selection_set, fragments, fragment_depths)
+                });
+                fragment_depths.insert(name, depth);
+                depth
+            }
+            Selection::InlineFragment(fragment) => {
+                selection_depth(&fragment.item.selection_set, fragments, fragment_depths)
+            }
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// the playground is only served by debug builds
+pub async fn graphiql() -> Result<HttpResponse, ServiceError> {
+    if !cfg!(debug_assertions) {
+        return Err(ServiceError::NotFound);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(graphiql_source("/api/graphql", None)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth(query: &str) -> usize {
+        query_depth(query, &create_schema())
+    }
+
+    #[test]
+    fn depth_counts_nested_fields() {
+        assert_eq!(depth("{ moods { id } }"), 2);
+        assert_eq!(
+            depth("{ moods { id } entries { entries { images { variants { url } } } } }"),
+            5
+        );
+        assert_eq!(
+            depth("query { moods { id } } mutation { deleteEntry(id: 1) }"),
+            2
+        );
+        assert_eq!(depth("{ moods { id"), 0);
+    }
+
+    #[test]
+    fn depth_follows_fragments() {
+        let query = "
+            { entries { ...Page } }
+            fragment Page on EntryPage { entries { ... on Entry { images { ...Image } } } }
+            fragment Image on EntryImage { variants { url } }
+        ";
+        assert_eq!(depth(query), 5);
+        assert_eq!(depth("{ moods { ...A } } fragment A on Mood { ...A }"), 1);
+    }
+
+    #[test]
+    fn deep_introspection_is_rejected() {
+        let type_ref = (0..MAX_QUERY_DEPTH).fold("name".to_string(), |inner, _| {
+            format!("ofType {{ {} }}", inner)
+        });
+        let query = format!(
+            "{{ __schema {{ types {{ fields {{ type {{ {} }} }} }} }} }}",
+            type_ref
+        );
+        let request: GraphQLBatchRequest =
+            serde_json::from_value(serde_json::json!({ "query": query })).unwrap();
+
+        assert!(matches!(
+            check_limits(&request, &create_schema()),
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn batches_are_limited() {
+        let batch = |count| -> GraphQLBatchRequest {
+            serde_json::from_value(serde_json::json!(vec![
+                serde_json::json!({ "query": "{ moods { id } }" });
+                count
+            ]))
+            .unwrap()
+        };
+
+        assert!(check_limits(&batch(MAX_BATCH_SIZE), &create_schema()).is_ok());
+        assert!(matches!(
+            check_limits(&batch(MAX_BATCH_SIZE + 1), &create_schema()),
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+}
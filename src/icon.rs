@@ -0,0 +1,66 @@
+use serde::{de, Deserialize, Deserializer};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::errors::ServiceError;
+
+// icon sets bundled with the frontend, referenced as `<set>:<name>`
+pub const ICON_SETS: &[&str] = &["mdi", "fa"];
+
+// an icon is either a single emoji / character or a named icon from a known set,
+// the named form may also be sent as a plain `"mdi:run"` string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum IconData {
+    Named { set: String, name: String },
+    Text(String),
+}
+
+fn named_icon(set: &str, name: &str) -> Result<String, String> {
+    if !ICON_SETS.contains(&set) {
+        return Err(format!("Unknown icon set: {}", set));
+    }
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid_name {
+        return Err(format!("Invalid icon name: {}", name));
+    }
+    Ok(format!("{}:{}", set, name))
+}
+
+fn parse_icon(icon: IconData) -> Result<String, String> {
+    match icon {
+        IconData::Named { set, name } => named_icon(&set, &name),
+        IconData::Text(text) => {
+            let mut graphemes = text.graphemes(true);
+            match (graphemes.next(), graphemes.next()) {
+                (Some(grapheme), None) if !grapheme.chars().all(char::is_whitespace) => Ok(text),
+                _ => match text.split_once(':') {
+                    Some((set, name)) => named_icon(set, name),
+                    None => Err("An icon must be a single emoji or character".to_string()),
+                },
+            }
+        }
+    }
+}
+
+// for icons that don't come through serde, e.g. GraphQL arguments
+pub fn validate_icon(icon: String) -> Result<String, ServiceError> {
+    parse_icon(IconData::Text(icon)).map_err(ServiceError::BadRequest)
+}
+
+pub fn deserialize_icon<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_icon(IconData::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+// for optional icons, pair it with `#[serde(default)]`
+pub fn deserialize_optional_icon<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_icon(deserializer).map(Some)
+}
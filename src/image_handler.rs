@@ -0,0 +1,433 @@
+use actix_multipart::Multipart;
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::{pg::Pg, prelude::*};
+use futures::StreamExt;
+use log::{error, info};
+
+use serde::Serialize;
+
+use crate::{
+    auth_handler::{ApiUser, LoggedUser, Scope},
+    errors::ServiceError,
+    image_processing::{
+        self, check_content_type, EncodedImage, ProcessedImage, MAX_IMAGE_SIZE, VARIANTS,
+    },
+    models::{EnrtyImage, EntryImageVariant, NewEntryImage, NewEntryImageVariant, Pool},
+    storage::{new_key, Storage},
+};
+
+// every part is buffered before any is processed, so a request may only carry this many
+const MAX_UPLOAD_COUNT: usize = 10;
+
+pub struct Upload {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BigImage {
+    #[serde(flatten)]
+    pub image: EnrtyImage,
+    pub variants: Vec<EntryImageVariant>,
+}
+
+pub fn load_big_images<C: Connection<Backend = Pg>>(
+    image_vec: Vec<EnrtyImage>,
+    conn: &C,
+) -> Result<Vec<BigImage>, ServiceError> {
+    use crate::schema::entry_image_variants::dsl::id;
+
+    let variant_groups = EntryImageVariant::belonging_to(&image_vec)
+        .order(id)
+        .get_results::<EntryImageVariant>(conn)?
+        .grouped_by(&image_vec);
+    Ok(image_vec
+        .into_iter()
+        .zip(variant_groups)
+        .map(|(image, variants)| BigImage { image, variants })
+        .collect())
+}
+
+// every file in the storage backend that belongs to the given images
+pub fn stored_keys(
+    image_vec: &[EnrtyImage],
+    conn: &PgConnection,
+) -> Result<Vec<String>, ServiceError> {
+    use crate::schema::entry_image_variants::dsl::storage_key;
+
+    let variant_keys = EntryImageVariant::belonging_to(image_vec)
+        .select(storage_key)
+        .get_results::<String>(conn)?;
+    Ok(image_vec
+        .iter()
+        .filter_map(|image| image.storage_key.clone())
+        .chain(variant_keys)
+        .collect())
+}
+
+// failing to remove a file only leaves an unreferenced object behind, so it is logged, not raised
+pub fn remove_stored_files<I: IntoIterator<Item = String>>(storage: &dyn Storage, keys: I) {
+    for key in keys {
+        if let Err(err) = storage.delete(&key) {
+            error!("Could not remove stored file {}: {}", key, err);
+        }
+    }
+}
+
+pub async fn upload_images(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    mut payload: Multipart,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::WriteEntries)?;
+    info!("Request to upload images by {}", logged_user.email);
+    let id = id.into_inner();
+
+    let mut uploads = Vec::new();
+    while let Some(field) = payload.next().await {
+        if uploads.len() == MAX_UPLOAD_COUNT {
+            return Err(ServiceError::BadRequest(format!(
+                "At most {} images may be uploaded at once",
+                MAX_UPLOAD_COUNT
+            )));
+        }
+        let mut field = field.map_err(|err| ServiceError::BadRequest(err.to_string()))?;
+        let content_type = field.content_type().essence_str().to_string();
+        check_content_type(&content_type)?;
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|err| ServiceError::BadRequest(err.to_string()))?;
+            if data.len() + chunk.len() > MAX_IMAGE_SIZE {
+                return Err(ServiceError::BadRequest(format!(
+                    "Images may not be larger than {} bytes",
+                    MAX_IMAGE_SIZE
+                )));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        uploads.push(Upload { content_type, data });
+    }
+    if uploads.is_empty() {
+        return Err(ServiceError::BadRequest("No image uploaded".to_string()));
+    }
+
+    let res =
+        web::block(move || upload_images_query(id, logged_user, uploads, pool, storage)).await;
+
+    match res {
+        Ok(images) => Ok(HttpResponse::Ok().json(&images)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn upload_images_query(
+    id: i32,
+    logged_user: LoggedUser,
+    uploads: Vec<Upload>,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<Vec<BigImage>, ServiceError> {
+    use crate::schema::entrys::dsl::{entrys, id as entrys_id, user_id};
+
+    let conn = &pool.get().unwrap();
+    let entry_id = entrys
+        .find(id)
+        .filter(user_id.eq(logged_user.id))
+        .select(entrys_id)
+        .get_result::<i32>(conn)?;
+
+    let processed = uploads
+        .iter()
+        .map(|upload| image_processing::process(&upload.content_type, &upload.data))
+        .collect::<Result<Vec<ProcessedImage>, ServiceError>>()?;
+
+    let prefix = format!("entries/{}/{}", logged_user.id, entry_id);
+    let mut stored = Vec::new();
+    let mut store = |encoded: &EncodedImage| {
+        let key = new_key(&prefix, encoded.extension);
+        storage.put(&key, encoded.content_type, &encoded.data)?;
+        stored.push(key.clone());
+        Ok(key)
+    };
+    let res = processed
+        .iter()
+        .map(|image| {
+            let original_key = store(&image.original)?;
+            let variant_keys = image
+                .variants
+                .iter()
+                .map(|(_, variant)| store(variant))
+                .collect::<Result<Vec<String>, ServiceError>>()?;
+            Ok((original_key, variant_keys))
+        })
+        .collect::<Result<Vec<(String, Vec<String>)>, ServiceError>>()
+        .and_then(|keys| {
+            conn.transaction(|| {
+                let mut images = Vec::new();
+                for (image, (original_key, variant_keys)) in processed.iter().zip(&keys) {
+                    images.push(insert_image(
+                        entry_id,
+                        logged_user.id,
+                        image,
+                        original_key,
+                        variant_keys,
+                        conn,
+                    )?);
+                }
+                load_big_images(images, conn)
+            })
+        });
+    if res.is_err() {
+        remove_stored_files(&**storage, stored);
+    }
+    res
+}
+
+fn insert_image(
+    entry_id: i32,
+    owner_id: i32,
+    processed: &ProcessedImage,
+    original_key: &str,
+    variant_keys: &[String],
+    conn: &PgConnection,
+) -> Result<EnrtyImage, ServiceError> {
+    use crate::schema::{
+        entry_image_variants::dsl::entry_image_variants,
+        entry_images::dsl::{entry_images, image_url},
+    };
+
+    let image = diesel::insert_into(entry_images)
+        .values(NewEntryImage {
+            user_id: owner_id,
+            entry_id,
+            image_url: "",
+            storage_key: Some(original_key),
+            content_type: Some(processed.original.content_type),
+        })
+        .get_result::<EnrtyImage>(conn)?;
+    // the urls point back at the authenticated downloads below
+    let image = diesel::update(&image)
+        .set(image_url.eq(format!("/api/entry/{}/images/{}", entry_id, image.id)))
+        .get_result::<EnrtyImage>(conn)?;
+
+    let urls: Vec<String> = processed
+        .variants
+        .iter()
+        .map(|(name, _)| format!("{}/{}", image.image_url, name))
+        .collect();
+    let variant_vec: Vec<NewEntryImageVariant> = processed
+        .variants
+        .iter()
+        .zip(variant_keys)
+        .zip(&urls)
+        .map(|(((name, variant), key), url)| NewEntryImageVariant {
+            image_id: image.id,
+            name,
+            width: variant.width as i32,
+            height: variant.height as i32,
+            url,
+            storage_key: key,
+            content_type: variant.content_type,
+        })
+        .collect();
+    diesel::insert_into(entry_image_variants)
+        .values(variant_vec)
+        .execute(conn)?;
+    Ok(image)
+}
+
+pub async fn get_images(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    let id = id.into_inner();
+    let res = web::block(move || get_images_query(id, logged_user, pool)).await;
+
+    match res {
+        Ok(images) => Ok(HttpResponse::Ok().json(&images)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn get_images_query(
+    id: i32,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<Vec<BigImage>, ServiceError> {
+    use crate::schema::{
+        entry_images::dsl::{entry_id as images_entry_id, entry_images, id as images_id},
+        entrys::dsl::{entrys, id as entrys_id, user_id},
+    };
+
+    let conn = &pool.get().unwrap();
+    let entry_id = entrys
+        .find(id)
+        .filter(user_id.eq(logged_user.id))
+        .select(entrys_id)
+        .get_result::<i32>(conn)?;
+    let images = entry_images
+        .filter(images_entry_id.eq(entry_id))
+        .order(images_id)
+        .get_results::<EnrtyImage>(conn)?;
+    load_big_images(images, conn)
+}
+
+fn find_image(
+    entry_id: i32,
+    image_id: i32,
+    logged_user: &LoggedUser,
+    conn: &PgConnection,
+) -> Result<EnrtyImage, ServiceError> {
+    use crate::schema::entry_images::dsl::{entry_id as images_entry_id, entry_images, user_id};
+
+    let image = entry_images
+        .find(image_id)
+        .filter(images_entry_id.eq(entry_id))
+        .filter(user_id.eq(logged_user.id))
+        .get_result::<EnrtyImage>(conn)?;
+    Ok(image)
+}
+
+pub async fn download_image(
+    api_user: ApiUser,
+    path: web::Path<(i32, i32)>,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    let (entry_id, image_id) = path.into_inner();
+    let res =
+        web::block(move || download_image_query(entry_id, image_id, logged_user, pool, storage))
+            .await;
+
+    match res {
+        Ok((content_type, data)) => Ok(HttpResponse::Ok().content_type(content_type).body(data)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn download_image_query(
+    entry_id: i32,
+    image_id: i32,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<(String, Vec<u8>), ServiceError> {
+    let conn = &pool.get().unwrap();
+    let image = find_image(entry_id, image_id, &logged_user, conn)?;
+    original_data(image, &**storage)
+}
+
+fn original_data(
+    image: EnrtyImage,
+    storage: &dyn Storage,
+) -> Result<(String, Vec<u8>), ServiceError> {
+    // linked images are served by whoever hosts them
+    let key = image.storage_key.ok_or(ServiceError::NotFound)?;
+    let data = storage.get(&key)?;
+    let content_type = image
+        .content_type
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    Ok((content_type, data))
+}
+
+pub async fn download_variant(
+    api_user: ApiUser,
+    path: web::Path<(i32, i32, String)>,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    let (entry_id, image_id, variant) = path.into_inner();
+    let res = web::block(move || {
+        download_variant_query(entry_id, image_id, variant, logged_user, pool, storage)
+    })
+    .await;
+
+    match res {
+        Ok((content_type, data)) => Ok(HttpResponse::Ok().content_type(content_type).body(data)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn download_variant_query(
+    entry_id: i32,
+    image_id: i32,
+    variant: String,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<(String, Vec<u8>), ServiceError> {
+    use crate::schema::entry_image_variants::dsl::name;
+
+    let conn = &pool.get().unwrap();
+    let image = find_image(entry_id, image_id, &logged_user, conn)?;
+    let stored = EntryImageVariant::belonging_to(&image)
+        .filter(name.eq(&variant))
+        .get_result::<EntryImageVariant>(conn)
+        .optional()?;
+    match stored {
+        Some(stored) => {
+            let data = storage.get(&stored.storage_key)?;
+            Ok((stored.content_type, data))
+        }
+        // no variant is stored for images that are no larger than it
+        None if VARIANTS.iter().any(|(known, _)| *known == variant) => {
+            original_data(image, &**storage)
+        }
+        None => Err(ServiceError::NotFound),
+    }
+}
+
+pub async fn delete_image(
+    api_user: ApiUser,
+    path: web::Path<(i32, i32)>,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::WriteEntries)?;
+    let (entry_id, image_id) = path.into_inner();
+    let res =
+        web::block(move || delete_image_query(entry_id, image_id, logged_user, pool, storage))
+            .await;
+
+    match res {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn delete_image_query(
+    entry_id: i32,
+    image_id: i32,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+) -> Result<(), ServiceError> {
+    let conn = &pool.get().unwrap();
+    let image = find_image(entry_id, image_id, &logged_user, conn)?;
+    let keys = stored_keys(std::slice::from_ref(&image), conn)?;
+    // variants are removed by ON DELETE CASCADE
+    diesel::delete(&image).execute(conn)?;
+    remove_stored_files(&**storage, keys);
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::{fmt::Display, io::Cursor};
+
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage, ExtendedColorType, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, Limits,
+};
+use log::error;
+
+use crate::errors::ServiceError;
+
+pub const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+const MAX_DIMENSION: u32 = 12_000;
+const JPEG_QUALITY: u8 = 85;
+
+// smaller copies stored next to every upload, bounded by their longest side
+pub const VARIANTS: &[(&str, u32)] = &[("thumb", 160), ("small", 480), ("medium", 1280)];
+
+pub struct EncodedImage {
+    pub content_type: &'static str,
+    pub extension: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct ProcessedImage {
+    pub original: EncodedImage,
+    pub variants: Vec<(&'static str, EncodedImage)>,
+}
+
+pub fn check_content_type(content_type: &str) -> Result<ImageFormat, ServiceError> {
+    match content_type {
+        "image/jpeg" => Ok(ImageFormat::Jpeg),
+        "image/png" => Ok(ImageFormat::Png),
+        "image/webp" => Ok(ImageFormat::WebP),
+        "image/gif" => Ok(ImageFormat::Gif),
+        _ => Err(ServiceError::BadRequest(format!(
+            "Unsupported image type: {}",
+            content_type
+        ))),
+    }
+}
+
+fn unreadable(err: impl Display) -> ServiceError {
+    ServiceError::BadRequest(format!("Unreadable image: {}", err))
+}
+
+// decodes the upload, applies its EXIF orientation and re-encodes it with all variants;
+// only pixels survive decoding, so EXIF and GPS data are never written back out
+pub fn process(content_type: &str, data: &[u8]) -> Result<ProcessedImage, ServiceError> {
+    let expected = check_content_type(content_type)?;
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(unreadable)?;
+    if reader.format() != Some(expected) {
+        return Err(ServiceError::BadRequest(format!(
+            "Image content does not match {}",
+            content_type
+        )));
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(unreadable)?;
+    let orientation = decoder.orientation().map_err(unreadable)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(unreadable)?;
+    image.apply_orientation(orientation);
+
+    // an image that already fits a variant's bounds is served in its place
+    let mut variants = Vec::new();
+    for (name, size) in VARIANTS {
+        if image.width() > *size || image.height() > *size {
+            let resized = image.resize(*size, *size, FilterType::Lanczos3);
+            variants.push((*name, encode(&resized)?));
+        }
+    }
+    Ok(ProcessedImage {
+        original: encode(&image)?,
+        variants,
+    })
+}
+
+// opaque images become JPEG, anything with transparency lossless WebP
+fn encode(image: &DynamicImage) -> Result<EncodedImage, ServiceError> {
+    let (width, height) = (image.width(), image.height());
+    let mut data = Vec::new();
+    let (res, content_type, extension) = if image.color().has_alpha() {
+        let res = WebPEncoder::new_lossless(&mut data).write_image(
+            &image.to_rgba8(),
+            width,
+            height,
+            ExtendedColorType::Rgba8,
+        );
+        (res, "image/webp", "webp")
+    } else {
+        let res = JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY).write_image(
+            &image.to_rgb8(),
+            width,
+            height,
+            ExtendedColorType::Rgb8,
+        );
+        (res, "image/jpeg", "jpg")
+    };
+    res.map_err(|err| {
+        error!("Could not encode image: {}", err);
+        ServiceError::InternalServerError
+    })?;
+    Ok(EncodedImage {
+        content_type,
+        extension,
+        width,
+        height,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn variants_are_only_made_to_shrink() {
+        let processed = process("image/png", &png(600, 300)).unwrap();
+        let variants: Vec<(&str, u32, u32)> = processed
+            .variants
+            .iter()
+            .map(|(name, variant)| (*name, variant.width, variant.height))
+            .collect();
+
+        assert_eq!(variants, vec![("thumb", 160, 80), ("small", 480, 240)]);
+        assert_eq!(
+            (processed.original.width, processed.original.height),
+            (600, 300)
+        );
+        assert!(process("image/png", &png(160, 90))
+            .unwrap()
+            .variants
+            .is_empty());
+    }
+}
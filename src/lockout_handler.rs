@@ -0,0 +1,354 @@
+use std::time::{Duration, SystemTime};
+
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+    auth_handler::LoggedUser,
+    errors::ServiceError,
+    mailer::{Mail, Mailer},
+    models::{
+        AccountUnlockToken, LoginAttempt, NewAccountUnlockToken, NewLoginAttempt, Pool, User,
+    },
+    session_handler::ClientInfo,
+    utils::{hash_token, new_token, FRONTEND_URL},
+};
+
+// failed checks an account gets before each further attempt has to wait,
+// 2, 4, 8, ... seconds after the previous one
+const ACCOUNT_FREE_ATTEMPTS: i32 = 3;
+// failed checks after which the account is locked and the owner gets an unlock link
+const LOCKOUT_THRESHOLD: i32 = 10;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(60 * 60);
+const UNLOCK_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+// the same for everything coming from one address, whichever accounts it tries
+const IP_FREE_ATTEMPTS: i64 = 10;
+const IP_WINDOW: Duration = Duration::from_secs(15 * 60);
+// 2^10 seconds are already longer than the window
+const IP_MAX_DOUBLINGS: i64 = 10;
+
+const HISTORY_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub enum LoginMethod {
+    Password,
+    TwoFactor,
+    Passkey,
+}
+
+impl LoginMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LoginMethod::Password => "password",
+            LoginMethod::TwoFactor => "two_factor",
+            LoginMethod::Passkey => "passkey",
+        }
+    }
+}
+
+// stored as `login_attempts.outcome`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoginOutcome {
+    Success,
+    TwoFactorRequired,
+    UnknownAccount,
+    WrongPassword,
+    InvalidCode,
+    InvalidPasskey,
+    Unverified,
+    // the address has to wait before trying again
+    Throttled,
+    // the account has to wait before trying again, or is locked
+    Locked,
+}
+
+impl LoginOutcome {
+    // the outcomes of guesses, which are what the per address backoff counts
+    const GUESSES: [LoginOutcome; 3] = [
+        LoginOutcome::UnknownAccount,
+        LoginOutcome::WrongPassword,
+        LoginOutcome::InvalidCode,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LoginOutcome::Success => "success",
+            LoginOutcome::TwoFactorRequired => "two_factor_required",
+            LoginOutcome::UnknownAccount => "unknown_account",
+            LoginOutcome::WrongPassword => "wrong_password",
+            LoginOutcome::InvalidCode => "invalid_code",
+            LoginOutcome::InvalidPasskey => "invalid_passkey",
+            LoginOutcome::Unverified => "unverified",
+            LoginOutcome::Throttled => "throttled",
+            LoginOutcome::Locked => "locked",
+        }
+    }
+}
+
+pub fn record_attempt(
+    user_id: Option<i32>,
+    email: &str,
+    method: LoginMethod,
+    outcome: LoginOutcome,
+    client: &ClientInfo,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::login_attempts::dsl::login_attempts;
+
+    diesel::insert_into(login_attempts)
+        .values(NewLoginAttempt {
+            user_id,
+            email,
+            method: method.as_str(),
+            outcome: outcome.as_str(),
+            ip: client.ip.as_deref(),
+            user_agent: client.user_agent.as_deref(),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+fn retry_after(until: SystemTime, now: SystemTime) -> Option<ServiceError> {
+    until
+        .duration_since(now)
+        .ok()
+        .map(|wait| ServiceError::TooManyRequests(wait.as_secs() + 1))
+}
+
+// rejects the attempt while the address is backing off from its recent guesses
+pub fn check_ip(client: &ClientInfo, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::login_attempts::dsl::{created_at, ip, login_attempts, outcome};
+
+    let address = match client.ip.as_deref() {
+        Some(address) => address,
+        None => return Ok(()),
+    };
+    let now = SystemTime::now();
+    // the backoff doubles with each guess up to the whole window, after that more don't matter
+    let guesses = login_attempts
+        .filter(ip.eq(address))
+        .filter(created_at.gt(now - IP_WINDOW))
+        .filter(outcome.eq_any(LoginOutcome::GUESSES.iter().map(|guess| guess.as_str())))
+        .select(created_at)
+        .order(created_at.desc())
+        .limit(IP_FREE_ATTEMPTS + IP_MAX_DOUBLINGS)
+        .get_results::<SystemTime>(conn)?;
+    let over_limit = guesses.len() as i64 - IP_FREE_ATTEMPTS;
+    if over_limit < 0 {
+        return Ok(());
+    }
+    let backoff = Duration::from_secs(1 << (over_limit + 1)).min(IP_WINDOW);
+    match retry_after(guesses[0] + backoff, now) {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// rejects the attempt while the account is backing off or locked
+fn check_account(user: &User) -> Result<(), ServiceError> {
+    match user
+        .locked_until
+        .and_then(|locked_until| retry_after(locked_until, SystemTime::now()))
+    {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+pub enum AccountCheck {
+    Passed(User),
+    // still backing off from earlier failures, the error says for how long
+    Locked(ServiceError),
+    Failed,
+}
+
+// runs `check`, a password or code comparison, with the account row locked until the result
+// is counted, so guesses sent in parallel are checked one after the other and each of them
+// sees the backoff the ones before it caused
+pub fn check_guarded<F>(
+    user_id: i32,
+    mailer: &dyn Mailer,
+    conn: &PgConnection,
+    check: F,
+) -> Result<AccountCheck, ServiceError>
+where
+    F: FnOnce(&User) -> Result<bool, ServiceError>,
+{
+    use crate::schema::users::dsl::users;
+
+    let (result, locked_out) = conn.transaction::<_, ServiceError, _>(|| {
+        let user = users.find(user_id).for_update().get_result::<User>(conn)?;
+        if let Err(err) = check_account(&user) {
+            return Ok((AccountCheck::Locked(err), None));
+        }
+        if check(&user)? {
+            return Ok((AccountCheck::Passed(user), None));
+        }
+        let locked_out = register_failure(&user, conn)?;
+        Ok((
+            AccountCheck::Failed,
+            if locked_out { Some(user) } else { None },
+        ))
+    })?;
+    // mailed once the row is no longer locked
+    if let Some(user) = locked_out {
+        send_unlock_mail(&user, mailer, conn)?;
+    }
+    Ok(result)
+}
+
+// counts a wrong password or code against the account, true once that locked it
+fn register_failure(user: &User, conn: &PgConnection) -> Result<bool, ServiceError> {
+    use crate::schema::users::dsl::{failed_logins, locked_until, users};
+
+    let failures = diesel::update(users.find(user.id))
+        .set(failed_logins.eq(failed_logins + 1))
+        .returning(failed_logins)
+        .get_result::<i32>(conn)?;
+    let now = SystemTime::now();
+    if failures >= LOCKOUT_THRESHOLD {
+        warn!(
+            "Locking account {} after {} failed logins",
+            user.id, failures
+        );
+        diesel::update(users.find(user.id))
+            .set(locked_until.eq(now + LOCKOUT_DURATION))
+            .execute(conn)?;
+        return Ok(true);
+    } else if failures > ACCOUNT_FREE_ATTEMPTS {
+        let backoff = Duration::from_secs(1 << (failures - ACCOUNT_FREE_ATTEMPTS));
+        diesel::update(users.find(user.id))
+            .set(locked_until.eq(now + backoff))
+            .execute(conn)?;
+    }
+    Ok(false)
+}
+
+pub fn register_success(user: &User, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::users::dsl::{failed_logins, locked_until};
+
+    if user.failed_logins != 0 || user.locked_until.is_some() {
+        diesel::update(user)
+            .set((failed_logins.eq(0), locked_until.eq(None::<SystemTime>)))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+fn send_unlock_mail(
+    user: &User,
+    mailer: &dyn Mailer,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::account_unlock_tokens::dsl::{account_unlock_tokens, used_at};
+
+    let token = new_token();
+    diesel::delete(AccountUnlockToken::belonging_to(user).filter(used_at.is_null()))
+        .execute(conn)?;
+    diesel::insert_into(account_unlock_tokens)
+        .values(NewAccountUnlockToken {
+            user_id: user.id,
+            token_hash: &hash_token(&token),
+            expires_at: SystemTime::now() + UNLOCK_TOKEN_LIFETIME,
+        })
+        .execute(conn)?;
+    mailer.send(Mail {
+        to: user.email.clone(),
+        subject: "Your account was locked".to_string(),
+        body: format!(
+            "There were too many failed attempts to log in to your account, so it was locked \
+             for the next hour.\n\n\
+             If it was you, open the link below to unlock it right away:\n\
+             {}/unlock-account?token={}\n\n\
+             If it wasn't you, someone may be guessing your password. \
+             Consider changing it once you are logged in again.\n",
+            FRONTEND_URL.trim_end_matches('/'),
+            token
+        ),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockData {
+    pub token: String,
+}
+
+pub async fn unlock_account(
+    unlock_data: web::Json<UnlockData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let unlock_data = unlock_data.into_inner();
+    let res = web::block(move || unlock_account_query(unlock_data, pool)).await;
+
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn unlock_account_query(
+    unlock_data: UnlockData,
+    pool: web::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::{
+        account_unlock_tokens::dsl::{account_unlock_tokens, expires_at, token_hash, used_at},
+        users::dsl::{failed_logins, locked_until, users},
+    };
+
+    let conn = &pool.get().unwrap();
+    conn.transaction(|| {
+        let now = SystemTime::now();
+        let unlock_token = diesel::update(
+            account_unlock_tokens
+                .filter(token_hash.eq(hash_token(&unlock_data.token)))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(now)),
+        )
+        .set(used_at.eq(now))
+        .get_result::<AccountUnlockToken>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::BadRequest("Invalid or expired unlock token".to_string()))?;
+        diesel::update(users.find(unlock_token.user_id))
+            .set((failed_logins.eq(0), locked_until.eq(None::<SystemTime>)))
+            .execute(conn)?;
+        info!("Account {} unlocked by mail", unlock_token.user_id);
+        Ok(())
+    })
+}
+
+// the latest attempts to log in to the account, including the failed ones
+pub async fn get_login_history(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let res = web::block(move || get_login_history_query(logged_user, pool)).await;
+
+    match res {
+        Ok(attempt_vec) => Ok(HttpResponse::Ok().json(&attempt_vec)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn get_login_history_query(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<Vec<LoginAttempt>, ServiceError> {
+    use crate::schema::login_attempts::dsl::{id, login_attempts, user_id};
+
+    let conn = &pool.get().unwrap();
+    let attempt_vec = login_attempts
+        .filter(user_id.eq(logged_user.id))
+        .order(id.desc())
+        .limit(HISTORY_LIMIT)
+        .get_results::<LoginAttempt>(conn)?;
+    Ok(attempt_vec)
+}
@@ -0,0 +1,121 @@
+use std::{fs, path::PathBuf, sync::Arc};
+
+use chrono::Utc;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+use log::{error, info};
+
+use crate::errors::ServiceError;
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// how outgoing mail is delivered, picked once at startup by `from_env`
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: Mail) -> Result<(), ServiceError>;
+}
+
+// MAILER selects the backend, `file` unless set to `smtp`
+pub fn from_env() -> Arc<dyn Mailer> {
+    let from: Mailbox = std::env::var("MAIL_FROM")
+        .unwrap_or_else(|_| "Moodtracker <noreply@localhost>".to_string())
+        .parse()
+        .expect("MAIL_FROM must be a valid address");
+    match std::env::var("MAILER").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer {
+            from,
+            transport: smtp_transport(),
+        }),
+        _ => Arc::new(FileMailer {
+            from,
+            outbox: std::env::var("MAIL_OUTBOX")
+                .unwrap_or_else(|_| "outbox".to_string())
+                .into(),
+        }),
+    }
+}
+
+// SMTP_TLS is `starttls` unless set to `tls` (implicit TLS) or `none`,
+// `none` is meant for local catchers like MailHog on port 1025
+fn smtp_transport() -> SmtpTransport {
+    let host = std::env::var("SMTP_HOST").expect("SMTP_HOST must be set");
+    let mut builder = match std::env::var("SMTP_TLS").as_deref() {
+        Ok("none") => SmtpTransport::builder_dangerous(&host),
+        Ok("tls") => SmtpTransport::relay(&host).expect("SMTP_HOST must be a valid host"),
+        _ => SmtpTransport::starttls_relay(&host).expect("SMTP_HOST must be a valid host"),
+    };
+    if let Ok(port) = std::env::var("SMTP_PORT") {
+        builder = builder.port(port.parse().expect("SMTP_PORT must be a port number"));
+    }
+    if let (Ok(username), Ok(password)) = (
+        std::env::var("SMTP_USERNAME"),
+        std::env::var("SMTP_PASSWORD"),
+    ) {
+        builder = builder.credentials(Credentials::new(username, password));
+    }
+    builder.build()
+}
+
+fn build_message(from: &Mailbox, mail: Mail) -> Result<Message, ServiceError> {
+    let to: Mailbox = mail
+        .to
+        .parse()
+        .map_err(|_| ServiceError::BadRequest(format!("Invalid email address: {}", mail.to)))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(mail.subject)
+        .body(mail.body)
+        .map_err(|err| {
+            error!("Could not build mail: {}", err);
+            ServiceError::InternalServerError
+        })
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: Mail) -> Result<(), ServiceError> {
+        let message = build_message(&self.from, mail)?;
+        self.transport.send(&message).map_err(|err| {
+            error!("Could not send mail: {}", err);
+            ServiceError::InternalServerError
+        })?;
+        Ok(())
+    }
+}
+
+// for development, every mail is written to the outbox directory as an .eml file
+pub struct FileMailer {
+    from: Mailbox,
+    outbox: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: Mail) -> Result<(), ServiceError> {
+        let (to, subject) = (mail.to.clone(), mail.subject.clone());
+        let message = build_message(&self.from, mail)?;
+        let id: [u8; 4] = rand::random();
+        let path = self.outbox.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            hex::encode(id)
+        ));
+        fs::create_dir_all(&self.outbox)
+            .and_then(|_| fs::write(&path, message.formatted()))
+            .map_err(|err| {
+                error!("Could not write mail: {}", err);
+                ServiceError::InternalServerError
+            })?;
+        info!("Mail to {} ({}) written to {}", to, subject, path.display());
+        Ok(())
+    }
+}
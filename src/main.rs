@@ -12,14 +12,35 @@ use auth_handler::LoggedUser;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
 
+mod account_handler;
 mod activity_handler;
 mod auth_handler;
 mod entry_handler;
 mod errors;
+mod graphql_handler;
+mod icon;
+mod image_handler;
+mod image_processing;
+mod lockout_handler;
+mod mailer;
 mod models;
 mod mood_handler;
+mod passkey_handler;
+mod password_handler;
 mod register_handler;
+// print_schema adds the custom type import to every table, not just the ones using it
+#[allow(unused_imports)]
 mod schema;
+mod search_handler;
+mod session_handler;
+mod sql_types;
+mod stats_handler;
+mod storage;
+#[cfg(test)]
+mod test_utils;
+mod token_handler;
+mod totp;
+mod two_factor_handler;
 mod utils;
 
 #[actix_web::main]
@@ -32,23 +53,39 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let frontend_url = std::env::var("FRONTEND_URL").expect("FRONTEND_URL must be set");
+    let frontend_url = utils::FRONTEND_URL.as_str();
 
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     let pool = r2d2::Pool::builder()
         .build(manager)
         .expect("Failed to create pool.");
     let domain = std::env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    let storage = web::Data::from(storage::from_env());
+    let mailer = web::Data::from(mailer::from_env());
+    let webauthn = web::Data::new(passkey_handler::webauthn_from_env());
+    let graphql_schema = web::Data::new(graphql_handler::create_schema());
+    // fail on startup instead of on the first login if these settings are invalid
+    lazy_static::initialize(&utils::ARGON2_CONFIG);
+    lazy_static::initialize(&auth_handler::UNVERIFIED_ACCESS);
+    lazy_static::initialize(&session_handler::TRUST_PROXY);
 
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_method()
             .allow_any_header()
-            .allowed_origin(&frontend_url)
+            .allowed_origin(frontend_url)
             .supports_credentials();
         App::new()
             .wrap(cors)
             .data(pool.clone())
+            .app_data(storage.clone())
+            .app_data(mailer.clone())
+            .app_data(webauthn.clone())
+            .app_data(graphql_schema.clone())
+            // report why a body was rejected, e.g. an invalid icon
+            .app_data(web::JsonConfig::default().error_handler(|err, _req| {
+                errors::ServiceError::BadRequest(err.to_string()).into()
+            }))
             // enable Logger
             .wrap(middleware::Logger::default())
             .wrap(IdentityService::new(
@@ -56,7 +93,7 @@ async fn main() -> std::io::Result<()> {
                     .name("auth")
                     .path("/")
                     .domain(domain.as_str())
-                    .max_age(86400) // one day in seconds
+                    .max_age(session_handler::SESSION_LIFETIME.as_secs() as i64)
                     .secure(false), // this can only be true if you have https
             ))
             .service(
@@ -67,28 +104,181 @@ async fn main() -> std::io::Result<()> {
                             .route(web::delete().to(auth_handler::logout))
                             .route(web::get().to(auth_handler::get_me)),
                     )
+                    .service(
+                        web::resource("/auth/2fa")
+                            .route(web::post().to(two_factor_handler::verify_login)),
+                    )
+                    .service(
+                        web::resource("/auth/2fa/totp")
+                            .route(web::post().to(two_factor_handler::begin_totp))
+                            .route(web::delete().to(two_factor_handler::disable_totp)),
+                    )
+                    .service(
+                        web::resource("/auth/2fa/totp/confirm")
+                            .route(web::post().to(two_factor_handler::confirm_totp)),
+                    )
+                    .service(
+                        web::resource("/auth/2fa/recovery-codes")
+                            .route(web::post().to(two_factor_handler::regenerate_recovery_codes)),
+                    )
+                    .service(
+                        web::resource("/auth/sessions")
+                            .route(web::get().to(session_handler::get_sessions))
+                            .route(web::delete().to(session_handler::revoke_all_sessions)),
+                    )
+                    .service(
+                        web::resource("/auth/sessions/{id}")
+                            .route(web::delete().to(session_handler::revoke_session)),
+                    )
+                    .service(
+                        web::resource("/auth/tokens")
+                            .route(web::get().to(token_handler::get_tokens))
+                            .route(web::post().to(token_handler::create_token)),
+                    )
+                    .service(
+                        web::resource("/auth/tokens/{id}")
+                            .route(web::delete().to(token_handler::delete_token)),
+                    )
+                    .service(
+                        web::resource("/auth/login-history")
+                            .route(web::get().to(lockout_handler::get_login_history)),
+                    )
+                    .service(
+                        web::resource("/auth/unlock")
+                            .route(web::post().to(lockout_handler::unlock_account)),
+                    )
+                    .service(
+                        web::resource("/auth/passkeys")
+                            .route(web::get().to(passkey_handler::get_passkeys)),
+                    )
+                    .service(
+                        web::resource("/auth/passkeys/register")
+                            .route(web::post().to(passkey_handler::start_registration)),
+                    )
+                    .service(
+                        web::resource("/auth/passkeys/register/finish")
+                            .route(web::post().to(passkey_handler::finish_registration)),
+                    )
+                    .service(
+                        web::resource("/auth/passkeys/login")
+                            .route(web::post().to(passkey_handler::start_login)),
+                    )
+                    .service(
+                        web::resource("/auth/passkeys/login/finish")
+                            .route(web::post().to(passkey_handler::finish_login)),
+                    )
+                    .service(
+                        web::resource("/auth/passkeys/{id}")
+                            .route(web::patch().to(passkey_handler::rename_passkey))
+                            .route(web::delete().to(passkey_handler::delete_passkey)),
+                    )
+                    .service(
+                        web::resource("/auth/password")
+                            .route(web::put().to(password_handler::change_password)),
+                    )
+                    .service(
+                        web::resource("/auth/password/reset")
+                            .route(web::post().to(password_handler::request_reset)),
+                    )
+                    .service(
+                        web::resource("/auth/password/reset/confirm")
+                            .route(web::post().to(password_handler::reset_password)),
+                    )
                     .service(
                         web::resource("/register")
                             .route(web::post().to(register_handler::register)),
                     )
+                    .service(
+                        web::resource("/register/verify")
+                            .route(web::post().to(register_handler::verify_email)),
+                    )
+                    .service(
+                        web::resource("/register/verify/resend")
+                            .route(web::post().to(register_handler::resend_verification)),
+                    )
+                    .service(
+                        web::resource("/account")
+                            .route(web::delete().to(account_handler::delete_account)),
+                    )
+                    .service(
+                        web::resource("/account/export")
+                            .route(web::get().to(account_handler::export_account)),
+                    )
+                    .service(
+                        web::resource("/graphql")
+                            .route(web::post().to(graphql_handler::graphql))
+                            .route(web::get().to(graphql_handler::graphiql)),
+                    )
+                    .service(
+                        web::resource("/stats/mood")
+                            .route(web::get().to(stats_handler::get_mood_stats)),
+                    )
+                    .service(
+                        web::resource("/stats/activities")
+                            .route(web::get().to(stats_handler::get_activity_impact)),
+                    )
+                    .service(
+                        web::resource("/stats/streaks")
+                            .route(web::get().to(stats_handler::get_streaks)),
+                    )
                     .service(
                         web::resource("/activity")
                             .route(web::post().to(activity_handler::create_activity))
                             .route(web::get().to(activity_handler::get_activities)),
                     )
+                    .service(
+                        web::resource("/activity/{id}")
+                            .route(web::patch().to(activity_handler::update_activity))
+                            .route(web::delete().to(activity_handler::delete_activity)),
+                    )
+                    .service(
+                        web::resource("/activity/{id}/merge")
+                            .route(web::post().to(activity_handler::merge_activity)),
+                    )
                     .service(
                         web::resource("/mood")
                             .route(web::post().to(mood_handler::create_mood))
                             .route(web::get().to(mood_handler::get_moods)),
                     )
+                    .service(
+                        web::resource("/mood/{id}")
+                            .route(web::patch().to(mood_handler::update_mood))
+                            .route(web::delete().to(mood_handler::delete_mood)),
+                    )
                     .service(
                         web::resource("/entry")
                             .route(web::get().to(entry_handler::get_entrys))
                             .route(web::post().to(entry_handler::create_entry)),
                     )
+                    .service(
+                        web::resource("/entry/search")
+                            .route(web::get().to(search_handler::search_entrys)),
+                    )
+                    .service(
+                        web::resource("/entry/search/language")
+                            .route(web::get().to(search_handler::get_search_language))
+                            .route(web::put().to(search_handler::set_search_language)),
+                    )
                     .service(
                         web::resource("/entry/{id}")
                             .route(web::get().to(entry_handler::get_entry_by_id))
+                            .route(web::put().to(entry_handler::update_entry))
+                            .route(web::patch().to(entry_handler::patch_entry))
+                            .route(web::delete().to(entry_handler::delete_entry)),
+                    )
+                    .service(
+                        web::resource("/entry/{id}/images")
+                            .route(web::get().to(image_handler::get_images))
+                            .route(web::post().to(image_handler::upload_images)),
+                    )
+                    .service(
+                        web::resource("/entry/{id}/images/{image_id}")
+                            .route(web::get().to(image_handler::download_image))
+                            .route(web::delete().to(image_handler::delete_image)),
+                    )
+                    .service(
+                        web::resource("/entry/{id}/images/{image_id}/{variant}")
+                            .route(web::get().to(image_handler::download_variant)),
                     ),
             )
             .route("/", web::get().to(index))
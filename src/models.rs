@@ -1,4 +1,4 @@
-use crate::schema::*;
+use crate::{icon::deserialize_optional_icon, schema::*};
 use diesel::{r2d2::ConnectionManager, PgConnection};
 use serde::{Deserialize, Serialize};
 
@@ -12,6 +12,17 @@ pub struct User {
     pub id: i32,
     pub email: String,
     pub hash: String,
+    pub search_language: String,
+    pub verified_at: Option<std::time::SystemTime>,
+    #[serde(skip)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<std::time::SystemTime>,
+    #[serde(skip)]
+    pub totp_last_step: Option<i64>,
+    #[serde(skip)]
+    pub failed_logins: i32,
+    #[serde(skip)]
+    pub locked_until: Option<std::time::SystemTime>,
 }
 
 #[derive(Debug, Insertable)]
@@ -30,10 +41,12 @@ impl NewUser {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SlimUser {
     pub id: i32,
     pub email: String,
+    pub verified: bool,
+    pub two_factor_enabled: bool,
 }
 
 impl From<User> for SlimUser {
@@ -41,11 +54,13 @@ impl From<User> for SlimUser {
         SlimUser {
             id: user.id,
             email: user.email,
+            verified: user.verified_at.is_some(),
+            two_factor_enabled: user.totp_enabled_at.is_some(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Queryable, Identifiable, Associations, AsChangeset)]
+#[derive(Debug, Clone, Serialize, Queryable, Identifiable, Associations, AsChangeset)]
 #[belongs_to(User)]
 pub struct Mood {
     pub id: i32,
@@ -53,6 +68,7 @@ pub struct Mood {
     pub name: String,
     pub value: i32,
     pub icon: String,
+    pub archived: bool,
 }
 
 #[derive(Debug, Insertable)]
@@ -64,6 +80,25 @@ pub struct NewMood {
     pub icon: String,
 }
 
+#[derive(Debug, Deserialize, AsChangeset)]
+#[table_name = "moods"]
+pub struct MoodChangeset {
+    pub name: Option<String>,
+    pub value: Option<i32>,
+    #[serde(default, deserialize_with = "deserialize_optional_icon")]
+    pub icon: Option<String>,
+    pub archived: Option<bool>,
+}
+
+impl MoodChangeset {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.value.is_none()
+            && self.icon.is_none()
+            && self.archived.is_none()
+    }
+}
+
 #[derive(Debug, Serialize, Queryable, Identifiable, Associations, AsChangeset)]
 #[belongs_to(User)]
 #[table_name = "activities"]
@@ -72,6 +107,7 @@ pub struct Activity {
     pub user_id: i32,
     pub name: String,
     pub icon: String,
+    pub archived: bool,
 }
 
 #[derive(Debug, Insertable)]
@@ -82,6 +118,21 @@ pub struct NewActivity<'a> {
     pub icon: String,
 }
 
+#[derive(Debug, Deserialize, AsChangeset)]
+#[table_name = "activities"]
+pub struct ActivityChangeset {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_icon")]
+    pub icon: Option<String>,
+    pub archived: Option<bool>,
+}
+
+impl ActivityChangeset {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.icon.is_none() && self.archived.is_none()
+    }
+}
+
 #[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
 #[belongs_to(User)]
 #[belongs_to(Mood)]
@@ -94,6 +145,21 @@ pub struct Entry {
     pub created_at: std::time::SystemTime,
 }
 
+// the columns backing `Entry`, leaving out the ones maintained for full-text search
+pub const ENTRY_COLUMNS: (
+    entrys::id,
+    entrys::user_id,
+    entrys::mood_id,
+    entrys::desc,
+    entrys::created_at,
+) = (
+    entrys::id,
+    entrys::user_id,
+    entrys::mood_id,
+    entrys::desc,
+    entrys::created_at,
+);
+
 #[derive(Debug, Insertable)]
 #[table_name = "entrys"]
 pub struct NewEntry {
@@ -103,6 +169,21 @@ pub struct NewEntry {
     pub created_at: Option<std::time::SystemTime>,
 }
 
+// `None` leaves a column untouched, `Some(None)` on `desc` clears it
+#[derive(Debug, AsChangeset)]
+#[table_name = "entrys"]
+pub struct EntryChangeset {
+    pub mood_id: Option<i32>,
+    pub desc: Option<Option<String>>,
+    pub created_at: Option<std::time::SystemTime>,
+}
+
+impl EntryChangeset {
+    pub fn is_empty(&self) -> bool {
+        self.mood_id.is_none() && self.desc.is_none() && self.created_at.is_none()
+    }
+}
+
 #[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
 #[belongs_to(User)]
 #[belongs_to(Entry)]
@@ -112,6 +193,9 @@ pub struct EnrtyImage {
     pub user_id: i32,
     pub entry_id: i32,
     pub image_url: String,
+    #[serde(skip)]
+    pub storage_key: Option<String>,
+    pub content_type: Option<String>,
 }
 
 #[derive(Debug, Insertable)]
@@ -120,6 +204,35 @@ pub struct NewEntryImage<'a> {
     pub user_id: i32,
     pub entry_id: i32,
     pub image_url: &'a str,
+    pub storage_key: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(EnrtyImage, foreign_key = "image_id")]
+#[table_name = "entry_image_variants"]
+pub struct EntryImageVariant {
+    pub id: i32,
+    pub image_id: i32,
+    pub name: String,
+    pub width: i32,
+    pub height: i32,
+    pub url: String,
+    #[serde(skip)]
+    pub storage_key: String,
+    pub content_type: String,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "entry_image_variants"]
+pub struct NewEntryImageVariant<'a> {
+    pub image_id: i32,
+    pub name: &'a str,
+    pub width: i32,
+    pub height: i32,
+    pub url: &'a str,
+    pub storage_key: &'a str,
+    pub content_type: &'a str,
 }
 
 #[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
@@ -138,3 +251,204 @@ pub struct NewEntryActivity {
     pub entry_id: i32,
     pub activity_id: i32,
 }
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "password_reset_tokens"]
+pub struct PasswordResetToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created_at: std::time::SystemTime,
+    pub expires_at: std::time::SystemTime,
+    pub used_at: Option<std::time::SystemTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "password_reset_tokens"]
+pub struct NewPasswordResetToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: std::time::SystemTime,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "email_verification_tokens"]
+pub struct EmailVerificationToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created_at: std::time::SystemTime,
+    pub expires_at: std::time::SystemTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "email_verification_tokens"]
+pub struct NewEmailVerificationToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: std::time::SystemTime,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "sessions"]
+pub struct Session {
+    pub id: i32,
+    pub user_id: i32,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: std::time::SystemTime,
+    pub last_seen_at: std::time::SystemTime,
+    pub expires_at: std::time::SystemTime,
+    #[serde(skip)]
+    pub two_factor_pending: bool,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "sessions"]
+pub struct NewSession<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub user_agent: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub expires_at: std::time::SystemTime,
+    pub two_factor_pending: bool,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "api_tokens"]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: std::time::SystemTime,
+    pub expires_at: Option<std::time::SystemTime>,
+    pub last_used_at: Option<std::time::SystemTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "api_tokens"]
+pub struct NewApiToken<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub token_hash: &'a str,
+    pub scopes: Vec<&'a str>,
+    pub expires_at: Option<std::time::SystemTime>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "recovery_codes"]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<std::time::SystemTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "recovery_codes"]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "passkeys"]
+pub struct Passkey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip)]
+    pub credential_id: String,
+    // the credential as serialized by webauthn-rs, with the public key and sign counter
+    #[serde(skip)]
+    pub passkey: String,
+    pub created_at: std::time::SystemTime,
+    pub last_used_at: Option<std::time::SystemTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "passkeys"]
+pub struct NewPasskey<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub credential_id: &'a str,
+    pub passkey: &'a str,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "passkey_challenges"]
+pub struct PasskeyChallenge {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub kind: String,
+    pub state: String,
+    pub expires_at: std::time::SystemTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "passkey_challenges"]
+pub struct NewPasskeyChallenge<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub kind: &'a str,
+    pub state: &'a str,
+    pub expires_at: std::time::SystemTime,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "login_attempts"]
+pub struct LoginAttempt {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub email: String,
+    pub method: String,
+    pub outcome: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: std::time::SystemTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "login_attempts"]
+pub struct NewLoginAttempt<'a> {
+    pub user_id: Option<i32>,
+    pub email: &'a str,
+    pub method: &'a str,
+    pub outcome: &'a str,
+    pub ip: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "account_unlock_tokens"]
+pub struct AccountUnlockToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created_at: std::time::SystemTime,
+    pub expires_at: std::time::SystemTime,
+    pub used_at: Option<std::time::SystemTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "account_unlock_tokens"]
+pub struct NewAccountUnlockToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: std::time::SystemTime,
+}
@@ -4,23 +4,26 @@ use log::info;
 use serde::Deserialize;
 
 use crate::{
-    auth_handler::LoggedUser,
+    auth_handler::{ApiUser, LoggedUser, Scope},
     errors::ServiceError,
-    models::{Mood, NewMood, Pool, User},
+    icon::deserialize_icon,
+    models::{Mood, MoodChangeset, NewMood, Pool, User},
 };
 
 #[derive(Debug, Deserialize)]
 pub struct MoodData {
     pub name: String,
+    #[serde(deserialize_with = "deserialize_icon")]
     pub icon: String,
     pub value: i32,
 }
 
 pub async fn create_mood(
-    logged_user: LoggedUser,
+    api_user: ApiUser,
     mood_data: web::Json<MoodData>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ManageMoodsActivities)?;
     info!("Request to create mood by {}", logged_user.email);
     let res =
         web::block(move || create_mood_query(logged_user, mood_data.into_inner(), pool)).await;
@@ -34,7 +37,7 @@ pub async fn create_mood(
     }
 }
 
-fn create_mood_query(
+pub fn create_mood_query(
     logged_user: LoggedUser,
     mood_data: MoodData,
     pool: web::Data<Pool>,
@@ -51,16 +54,25 @@ fn create_mood_query(
     let inserted_mood = diesel::insert_into(moods)
         .values(&new_mood)
         .get_result(conn)?;
-    dbg!(&&inserted_mood);
     Ok(inserted_mood)
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MoodQuery {
+    // archived moods are hidden from pickers unless asked for
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
 pub async fn get_moods(
-    logged_user: LoggedUser,
+    api_user: ApiUser,
+    mood_query: web::Query<MoodQuery>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.any_scope();
     info!("Request to get moods by {}", logged_user.email);
-    let res = web::block(move || get_moods_query(logged_user, pool)).await;
+    let mood_query = mood_query.into_inner();
+    let res = web::block(move || get_moods_query(logged_user, mood_query, pool)).await;
 
     match res {
         Ok(moods) => Ok(HttpResponse::Ok().json(&moods)),
@@ -71,18 +83,141 @@ pub async fn get_moods(
     }
 }
 
-fn get_moods_query(
+pub fn get_moods_query(
     logged_user: LoggedUser,
+    mood_query: MoodQuery,
     pool: web::Data<Pool>,
 ) -> Result<Vec<Mood>, ServiceError> {
-    use crate::schema::moods::dsl::value;
+    use crate::schema::moods::dsl::{archived, value};
     use crate::schema::users::dsl::users;
 
     let conn = &pool.get().unwrap();
     let user = users.find(logged_user.id).get_result::<User>(conn)?;
-    let moods = Mood::belonging_to(&user)
-        .order(value.desc())
-        .get_results(conn)?;
+    let mut query = Mood::belonging_to(&user).into_boxed();
+    if !mood_query.include_archived {
+        query = query.filter(archived.eq(false));
+    }
+    let moods = query.order(value.desc()).get_results(conn)?;
 
     Ok(moods)
 }
+
+pub async fn update_mood(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    mood_data: web::Json<MoodChangeset>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ManageMoodsActivities)?;
+    info!("Request to update mood by {}", logged_user.email);
+    let id = id.into_inner();
+    let mood_data = mood_data.into_inner();
+    let res = web::block(move || update_mood_query(id, logged_user, mood_data, pool)).await;
+
+    match res {
+        Ok(mood) => Ok(HttpResponse::Ok().json(&mood)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+pub fn update_mood_query(
+    id: i32,
+    logged_user: LoggedUser,
+    mood_data: MoodChangeset,
+    pool: web::Data<Pool>,
+) -> Result<Mood, ServiceError> {
+    use crate::schema::moods::dsl::{moods, user_id};
+
+    let conn = &pool.get().unwrap();
+    let mood = moods
+        .find(id)
+        .filter(user_id.eq(logged_user.id))
+        .get_result::<Mood>(conn)?;
+    if mood_data.is_empty() {
+        return Ok(mood);
+    }
+    let updated_mood = diesel::update(&mood).set(&mood_data).get_result(conn)?;
+    Ok(updated_mood)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteMoodQuery {
+    // mood that takes over the entries of the deleted one
+    pub reassign_to: Option<i32>,
+}
+
+pub async fn delete_mood(
+    api_user: ApiUser,
+    id: web::Path<i32>,
+    delete_query: web::Query<DeleteMoodQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ManageMoodsActivities)?;
+    info!("Request to delete mood by {}", logged_user.email);
+    let id = id.into_inner();
+    let delete_query = delete_query.into_inner();
+    let res = web::block(move || delete_mood_query(id, logged_user, delete_query, pool)).await;
+
+    match res {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+pub fn delete_mood_query(
+    id: i32,
+    logged_user: LoggedUser,
+    delete_query: DeleteMoodQuery,
+    pool: web::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::{
+        entrys::dsl::{entrys, mood_id},
+        moods::dsl::{moods, user_id},
+    };
+
+    let conn = &pool.get().unwrap();
+    conn.transaction(|| {
+        let mood = moods
+            .find(id)
+            .filter(user_id.eq(logged_user.id))
+            .get_result::<Mood>(conn)?;
+
+        match delete_query.reassign_to {
+            Some(target_id) => {
+                if target_id == mood.id {
+                    return Err(ServiceError::BadRequest(
+                        "A mood cannot be reassigned to itself".to_string(),
+                    ));
+                }
+                let target = moods
+                    .find(target_id)
+                    .filter(user_id.eq(logged_user.id))
+                    .get_result::<Mood>(conn)?;
+                diesel::update(entrys.filter(mood_id.eq(mood.id)))
+                    .set(mood_id.eq(target.id))
+                    .execute(conn)?;
+            }
+            None => {
+                let used_by = entrys
+                    .filter(mood_id.eq(mood.id))
+                    .count()
+                    .get_result::<i64>(conn)?;
+                if used_by > 0 {
+                    return Err(ServiceError::Conflict(format!(
+                        "Mood is used by {} entries, reassign them first",
+                        used_by
+                    )));
+                }
+            }
+        }
+
+        diesel::delete(&mood).execute(conn)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,632 @@
+use std::time::{Duration, SystemTime};
+
+use actix_identity::Identity;
+use actix_web::{error::BlockingError, web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential, RequestChallengeResponse, Url, Uuid, Webauthn, WebauthnBuilder,
+};
+
+use crate::{
+    auth_handler::{check_may_log_in, LoggedUser},
+    errors::ServiceError,
+    lockout_handler::{record_attempt, LoginMethod, LoginOutcome},
+    models::{NewPasskey, NewPasskeyChallenge, Passkey, PasskeyChallenge, Pool, SlimUser, User},
+    session_handler::{create_session, ClientInfo},
+    utils::{hash_token, new_token, FRONTEND_URL, SECRET_KEY},
+};
+
+// shown by the browser when it asks which passkey to use
+const RP_NAME: &str = "Moodtracker";
+// the browser gives the user five minutes to touch the authenticator
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+const REGISTRATION: &str = "registration";
+const AUTHENTICATION: &str = "authentication";
+
+// the relying party id is the domain passkeys are bound to, WEBAUTHN_RP_ID defaults to DOMAIN;
+// the browser runs the ceremonies on the frontend, so that is the expected origin
+pub fn webauthn_from_env() -> Webauthn {
+    let rp_id = std::env::var("WEBAUTHN_RP_ID")
+        .or_else(|_| std::env::var("DOMAIN"))
+        .unwrap_or_else(|_| "localhost".to_string());
+    let origin = Url::parse(&FRONTEND_URL).expect("FRONTEND_URL must be a valid url");
+    WebauthnBuilder::new(&rp_id, &origin)
+        .and_then(|builder| builder.rp_name(RP_NAME).build())
+        .expect("WEBAUTHN_RP_ID must be the domain of FRONTEND_URL or a parent of it")
+}
+
+// authenticators store a user handle next to the credential, it shouldn't give away the
+// user id, so it is derived from it with the secret key
+fn user_handle(user_id: i32) -> Uuid {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(SECRET_KEY.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("passkey-user:{}", user_id).as_bytes());
+    let hash = mac.finalize().into_bytes();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    Uuid::from_bytes(bytes)
+}
+
+fn load_credential(passkey: &Passkey) -> Result<webauthn_rs::prelude::Passkey, ServiceError> {
+    serde_json::from_str(&passkey.passkey).map_err(|err| {
+        error!("Could not load stored passkey {}: {}", passkey.id, err);
+        ServiceError::InternalServerError
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, ServiceError> {
+    serde_json::to_string(value).map_err(|err| {
+        error!("Could not serialize passkey data: {}", err);
+        ServiceError::InternalServerError
+    })
+}
+
+// the ceremony state stays on the server, the client only gets a token to refer to it
+fn store_challenge<T: Serialize>(
+    user_id: i32,
+    challenge_kind: &str,
+    ceremony_state: &T,
+    conn: &PgConnection,
+) -> Result<String, ServiceError> {
+    use crate::schema::passkey_challenges::dsl::{
+        expires_at, passkey_challenges, user_id as challenges_user_id,
+    };
+
+    let now = SystemTime::now();
+    diesel::delete(
+        passkey_challenges
+            .filter(challenges_user_id.eq(user_id))
+            .filter(expires_at.le(now)),
+    )
+    .execute(conn)?;
+    let token = new_token();
+    diesel::insert_into(passkey_challenges)
+        .values(NewPasskeyChallenge {
+            user_id,
+            token_hash: &hash_token(&token),
+            kind: challenge_kind,
+            state: &to_json(ceremony_state)?,
+            expires_at: now + CHALLENGE_LIFETIME,
+        })
+        .execute(conn)?;
+    Ok(token)
+}
+
+// deleting the challenge in the same statement that finds it keeps it single-use
+fn take_challenge(
+    token: &str,
+    challenge_kind: &str,
+    conn: &PgConnection,
+) -> Result<PasskeyChallenge, ServiceError> {
+    use crate::schema::passkey_challenges::dsl::{
+        expires_at, kind, passkey_challenges, token_hash,
+    };
+
+    diesel::delete(
+        passkey_challenges
+            .filter(token_hash.eq(hash_token(token)))
+            .filter(kind.eq(challenge_kind))
+            .filter(expires_at.gt(SystemTime::now())),
+    )
+    .get_result::<PasskeyChallenge>(conn)
+    .optional()?
+    .ok_or_else(|| ServiceError::BadRequest("Invalid or expired passkey challenge".to_string()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegistrationChallenge {
+    pub challenge_token: String,
+    // pass this to navigator.credentials.create()
+    pub options: CreationChallengeResponse,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthenticationChallenge {
+    pub challenge_token: String,
+    // pass this to navigator.credentials.get()
+    pub options: RequestChallengeResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationData {
+    pub challenge_token: String,
+    pub name: String,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginStartData {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginData {
+    pub challenge_token: String,
+    pub credential: PublicKeyCredential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyName {
+    pub name: String,
+}
+
+fn validate_name(name: &str) -> Result<&str, ServiceError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ServiceError::BadRequest(
+            "A passkey needs a name".to_string(),
+        ));
+    }
+    Ok(name)
+}
+
+pub async fn start_registration(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<HttpResponse, ServiceError> {
+    info!("Request to register a passkey by {}", logged_user.email);
+    let res = web::block(move || start_registration_query(logged_user, pool, webauthn)).await;
+
+    match res {
+        Ok(challenge) => Ok(HttpResponse::Ok().json(&challenge)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn start_registration_query(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<RegistrationChallenge, ServiceError> {
+    use crate::schema::passkeys::dsl::{passkeys, user_id};
+
+    let conn = &pool.get().unwrap();
+    // the authenticator refuses to register a second passkey for the same account
+    let existing = passkeys
+        .filter(user_id.eq(logged_user.id))
+        .get_results::<Passkey>(conn)?
+        .iter()
+        .map(|passkey| load_credential(passkey).map(|credential| credential.cred_id().clone()))
+        .collect::<Result<Vec<_>, ServiceError>>()?;
+    let (options, registration) = webauthn
+        .start_passkey_registration(
+            user_handle(logged_user.id),
+            &logged_user.email,
+            &logged_user.email,
+            Some(existing),
+        )
+        .map_err(|err| {
+            error!("Could not start passkey registration: {}", err);
+            ServiceError::InternalServerError
+        })?;
+    let challenge_token = store_challenge(logged_user.id, REGISTRATION, &registration, conn)?;
+    Ok(RegistrationChallenge {
+        challenge_token,
+        options,
+    })
+}
+
+pub async fn finish_registration(
+    logged_user: LoggedUser,
+    registration_data: web::Json<RegistrationData>,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<HttpResponse, ServiceError> {
+    let registration_data = registration_data.into_inner();
+    let res = web::block(move || {
+        finish_registration_query(logged_user, registration_data, pool, webauthn)
+    })
+    .await;
+
+    match res {
+        Ok(passkey) => Ok(HttpResponse::Ok().json(&passkey)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn finish_registration_query(
+    logged_user: LoggedUser,
+    registration_data: RegistrationData,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<Passkey, ServiceError> {
+    use crate::schema::passkeys::dsl::passkeys;
+
+    let name = validate_name(&registration_data.name)?;
+    let conn = &pool.get().unwrap();
+    let challenge = take_challenge(&registration_data.challenge_token, REGISTRATION, conn)?;
+    if challenge.user_id != logged_user.id {
+        return Err(ServiceError::BadRequest(
+            "Invalid or expired passkey challenge".to_string(),
+        ));
+    }
+    let registration: PasskeyRegistration =
+        serde_json::from_str(&challenge.state).map_err(|err| {
+            error!("Could not load passkey registration state: {}", err);
+            ServiceError::InternalServerError
+        })?;
+    let credential = webauthn
+        .finish_passkey_registration(&registration_data.credential, &registration)
+        .map_err(|err| {
+            info!(
+                "Passkey registration by {} failed: {}",
+                logged_user.email, err
+            );
+            ServiceError::BadRequest("The passkey could not be verified".to_string())
+        })?;
+    let passkey = diesel::insert_into(passkeys)
+        .values(NewPasskey {
+            user_id: logged_user.id,
+            name,
+            credential_id: &hex::encode(credential.cred_id()),
+            passkey: &to_json(&credential)?,
+        })
+        .get_result::<Passkey>(conn)?;
+    Ok(passkey)
+}
+
+// the user picks their account by email first, then the browser offers their passkeys
+pub async fn start_login(
+    login_data: web::Json<LoginStartData>,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<HttpResponse, ServiceError> {
+    let login_data = login_data.into_inner();
+    let res = web::block(move || start_login_query(login_data, pool, webauthn)).await;
+
+    match res {
+        Ok(challenge) => Ok(HttpResponse::Ok().json(&challenge)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn start_login_query(
+    login_data: LoginStartData,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<AuthenticationChallenge, ServiceError> {
+    use crate::schema::users::dsl::{email, users};
+
+    let conn = &pool.get().unwrap();
+    // unknown accounts and accounts without passkeys look the same as for the password login
+    let user = users
+        .filter(email.eq(&login_data.email))
+        .get_result::<User>(conn)
+        .optional()?
+        .ok_or(ServiceError::Unauthorized)?;
+    let credentials = Passkey::belonging_to(&user)
+        .get_results::<Passkey>(conn)?
+        .iter()
+        .map(load_credential)
+        .collect::<Result<Vec<_>, ServiceError>>()?;
+    if credentials.is_empty() {
+        return Err(ServiceError::Unauthorized);
+    }
+    let (options, authentication) = webauthn
+        .start_passkey_authentication(&credentials)
+        .map_err(|err| {
+            error!("Could not start passkey authentication: {}", err);
+            ServiceError::InternalServerError
+        })?;
+    let challenge_token = store_challenge(user.id, AUTHENTICATION, &authentication, conn)?;
+    Ok(AuthenticationChallenge {
+        challenge_token,
+        options,
+    })
+}
+
+pub async fn finish_login(
+    req: HttpRequest,
+    login_data: web::Json<LoginData>,
+    id: Identity,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<HttpResponse, ServiceError> {
+    let client = ClientInfo::from_request(&req);
+    let login_data = login_data.into_inner();
+    let res = web::block(move || finish_login_query(login_data, client, pool, webauthn)).await;
+
+    match res {
+        Ok((user, token)) => {
+            id.remember(token);
+            Ok(HttpResponse::Ok().json(user))
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn finish_login_query(
+    login_data: LoginData,
+    client: ClientInfo,
+    pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+) -> Result<(SlimUser, String), ServiceError> {
+    use crate::schema::{
+        passkeys::dsl::{credential_id, last_used_at, passkey as passkey_json},
+        users::dsl::users,
+    };
+
+    let conn = &pool.get().unwrap();
+    let challenge = take_challenge(&login_data.challenge_token, AUTHENTICATION, conn)?;
+    let authentication: PasskeyAuthentication =
+        serde_json::from_str(&challenge.state).map_err(|err| {
+            error!("Could not load passkey authentication state: {}", err);
+            ServiceError::InternalServerError
+        })?;
+    let user = users.find(challenge.user_id).get_result::<User>(conn)?;
+    let record = |outcome: LoginOutcome| {
+        record_attempt(
+            Some(user.id),
+            &user.email,
+            LoginMethod::Passkey,
+            outcome,
+            &client,
+            conn,
+        )
+    };
+
+    // a passkey can't be guessed, so the password lockout doesn't apply to it
+    let result =
+        match webauthn.finish_passkey_authentication(&login_data.credential, &authentication) {
+            Ok(result) => Some(result),
+            Err(err) => {
+                info!("Passkey login of user {} failed: {}", user.id, err);
+                None
+            }
+        };
+    let passkey = match result {
+        Some(ref result) => Passkey::belonging_to(&user)
+            .filter(credential_id.eq(hex::encode(result.cred_id())))
+            .get_result::<Passkey>(conn)
+            .optional()?,
+        None => None,
+    };
+    let (result, passkey) = match (result, passkey) {
+        (Some(result), Some(passkey)) => (result, passkey),
+        _ => {
+            record(LoginOutcome::InvalidPasskey)?;
+            return Err(ServiceError::Unauthorized);
+        }
+    };
+    if let Err(err) = check_may_log_in(&user) {
+        record(LoginOutcome::Unverified)?;
+        return Err(err);
+    }
+
+    // the sign counter is kept up to date so cloned authenticators can be detected
+    let mut credential = load_credential(&passkey)?;
+    credential.update_credential(&result);
+    diesel::update(&passkey)
+        .set((
+            passkey_json.eq(to_json(&credential)?),
+            last_used_at.eq(SystemTime::now()),
+        ))
+        .execute(conn)?;
+    // a passkey already proves possession and user verification, so there is no second factor
+    record(LoginOutcome::Success)?;
+    let token = create_session(user.id, &client, false, conn)?;
+    Ok((user.into(), token))
+}
+
+pub async fn get_passkeys(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let res = web::block(move || get_passkeys_query(logged_user, pool)).await;
+
+    match res {
+        Ok(passkey_vec) => Ok(HttpResponse::Ok().json(&passkey_vec)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn get_passkeys_query(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<Vec<Passkey>, ServiceError> {
+    use crate::schema::passkeys::dsl::{id, passkeys, user_id};
+
+    let conn = &pool.get().unwrap();
+    let passkey_vec = passkeys
+        .filter(user_id.eq(logged_user.id))
+        .order(id)
+        .get_results::<Passkey>(conn)?;
+    Ok(passkey_vec)
+}
+
+pub async fn rename_passkey(
+    logged_user: LoggedUser,
+    passkey_id: web::Path<i32>,
+    name_data: web::Json<PasskeyName>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let passkey_id = passkey_id.into_inner();
+    let name_data = name_data.into_inner();
+    let res =
+        web::block(move || rename_passkey_query(passkey_id, name_data, logged_user, pool)).await;
+
+    match res {
+        Ok(passkey) => Ok(HttpResponse::Ok().json(&passkey)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn rename_passkey_query(
+    passkey_id: i32,
+    name_data: PasskeyName,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<Passkey, ServiceError> {
+    use crate::schema::passkeys::dsl::{name, passkeys, user_id};
+
+    let new_name = validate_name(&name_data.name)?;
+    let conn = &pool.get().unwrap();
+    let passkey = diesel::update(passkeys.find(passkey_id).filter(user_id.eq(logged_user.id)))
+        .set(name.eq(new_name))
+        .get_result::<Passkey>(conn)?;
+    Ok(passkey)
+}
+
+pub async fn delete_passkey(
+    logged_user: LoggedUser,
+    passkey_id: web::Path<i32>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let passkey_id = passkey_id.into_inner();
+    let res = web::block(move || delete_passkey_query(passkey_id, logged_user, pool)).await;
+
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn delete_passkey_query(
+    passkey_id: i32,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::passkeys::dsl::{passkeys, user_id};
+
+    let conn = &pool.get().unwrap();
+    let deleted = diesel::delete(passkeys.find(passkey_id).filter(user_id.eq(logged_user.id)))
+        .execute(conn)?;
+    if deleted == 0 {
+        return Err(ServiceError::NotFound);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+    use super::*;
+    use crate::test_utils::{pool, TestUser};
+
+    const ORIGIN: &str = "http://localhost:3000";
+
+    fn webauthn() -> web::Data<Webauthn> {
+        let origin = Url::parse(ORIGIN).unwrap();
+        web::Data::new(
+            WebauthnBuilder::new("localhost", &origin)
+                .unwrap()
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            user_agent: None,
+            ip: None,
+        }
+    }
+
+    fn is_stale_challenge<T>(result: Result<T, ServiceError>) -> bool {
+        matches!(result, Err(ServiceError::BadRequest(message))
+            if message == "Invalid or expired passkey challenge")
+    }
+
+    #[test]
+    fn passkey_ceremonies_use_each_challenge_once() {
+        let pool = web::Data::new(pool());
+        let webauthn = webauthn();
+        let test_user = TestUser::create(&pool);
+        let logged_user = LoggedUser {
+            id: test_user.user.id,
+            email: test_user.user.email.clone(),
+            verified: true,
+            two_factor_enabled: false,
+        };
+        // a software authenticator that claims to have verified the user
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+        let challenge =
+            start_registration_query(logged_user.clone(), pool.clone(), webauthn.clone()).unwrap();
+        let credential = authenticator
+            .do_registration(Url::parse(ORIGIN).unwrap(), challenge.options)
+            .unwrap();
+        let challenge_token = challenge.challenge_token;
+        let registration_data = || RegistrationData {
+            challenge_token: challenge_token.clone(),
+            name: "Soft passkey".to_string(),
+            credential: credential.clone(),
+        };
+        let passkey = finish_registration_query(
+            logged_user.clone(),
+            registration_data(),
+            pool.clone(),
+            webauthn.clone(),
+        )
+        .unwrap();
+        assert_eq!(passkey.user_id, logged_user.id);
+        assert!(is_stale_challenge(finish_registration_query(
+            logged_user.clone(),
+            registration_data(),
+            pool.clone(),
+            webauthn.clone(),
+        )));
+
+        let login_start = || LoginStartData {
+            email: logged_user.email.clone(),
+        };
+        let challenge = start_login_query(login_start(), pool.clone(), webauthn.clone()).unwrap();
+        let credential = authenticator
+            .do_authentication(Url::parse(ORIGIN).unwrap(), challenge.options)
+            .unwrap();
+        let challenge_token = challenge.challenge_token;
+        let login_data = || LoginData {
+            challenge_token: challenge_token.clone(),
+            credential: credential.clone(),
+        };
+        let (user, _) =
+            finish_login_query(login_data(), client(), pool.clone(), webauthn.clone()).unwrap();
+        assert_eq!(user.id, logged_user.id);
+        assert!(is_stale_challenge(finish_login_query(
+            login_data(),
+            client(),
+            pool.clone(),
+            webauthn.clone(),
+        )));
+
+        // the passkey keeps working with a fresh challenge
+        let challenge = start_login_query(login_start(), pool.clone(), webauthn.clone()).unwrap();
+        let credential = authenticator
+            .do_authentication(Url::parse(ORIGIN).unwrap(), challenge.options)
+            .unwrap();
+        let login_data = LoginData {
+            challenge_token: challenge.challenge_token,
+            credential,
+        };
+        assert!(finish_login_query(login_data, client(), pool.clone(), webauthn).is_ok());
+    }
+}
@@ -0,0 +1,213 @@
+use std::time::{Duration, SystemTime};
+
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use log::{error, info};
+use serde::Deserialize;
+
+use crate::{
+    auth_handler::CurrentSession,
+    errors::ServiceError,
+    lockout_handler::{check_guarded, register_success, AccountCheck},
+    mailer::{Mail, Mailer},
+    models::{NewPasswordResetToken, PasswordResetToken, Pool, User},
+    session_handler::revoke_sessions,
+    utils::{hash_password, hash_token, new_token, validate_password, verify, FRONTEND_URL},
+};
+
+const RESET_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordData {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+pub async fn change_password(
+    current: CurrentSession,
+    password_data: web::Json<ChangePasswordData>,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<HttpResponse, ServiceError> {
+    info!("Request to change password by {}", current.user.email);
+    let password_data = password_data.into_inner();
+    let res = web::block(move || change_password_query(current, password_data, pool, mailer)).await;
+
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn change_password_query(
+    current: CurrentSession,
+    password_data: ChangePasswordData,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<(), ServiceError> {
+    use crate::schema::users::dsl::hash;
+
+    let conn = &pool.get().unwrap();
+    // wrong guesses count against the account like failed logins do,
+    // so a stolen session can't be used to try passwords
+    let user = match check_guarded(current.user.id, &**mailer, conn, |user| {
+        verify(&user.hash, &password_data.current_password)
+    })? {
+        AccountCheck::Passed(user) => user,
+        AccountCheck::Locked(err) => return Err(err),
+        AccountCheck::Failed => {
+            return Err(ServiceError::Forbidden(
+                "Current password is incorrect".to_string(),
+            ))
+        }
+    };
+    register_success(&user, conn)?;
+    validate_password(&password_data.new_password)?;
+    conn.transaction(|| {
+        diesel::update(&user)
+            .set(hash.eq(hash_password(&password_data.new_password)?))
+            .execute(conn)?;
+        // everyone who might know the old password is logged out, except this session
+        revoke_sessions(user.id, Some(current.session.id), conn)?;
+        Ok(())
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetRequestData {
+    pub email: String,
+}
+
+// always answers 200 so the endpoint can't be used to find out which addresses have an account
+pub async fn request_reset(
+    reset_data: web::Json<ResetRequestData>,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<HttpResponse, ServiceError> {
+    let reset_data = reset_data.into_inner();
+    let res = web::block(move || request_reset_query(reset_data, pool, mailer)).await;
+
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn request_reset_query(
+    reset_data: ResetRequestData,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<(), ServiceError> {
+    use crate::schema::{
+        password_reset_tokens::dsl::{password_reset_tokens, used_at},
+        users::dsl::{email, users},
+    };
+
+    let conn = &pool.get().unwrap();
+    let user = match users
+        .filter(email.eq(&reset_data.email))
+        .get_result::<User>(conn)
+        .optional()?
+    {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    // only the hash is stored, the token itself only ever exists in the mail
+    let token = new_token();
+    conn.transaction::<_, ServiceError, _>(|| {
+        // a new request replaces any link that was sent before
+        diesel::delete(PasswordResetToken::belonging_to(&user).filter(used_at.is_null()))
+            .execute(conn)?;
+        diesel::insert_into(password_reset_tokens)
+            .values(NewPasswordResetToken {
+                user_id: user.id,
+                token_hash: &hash_token(&token),
+                expires_at: SystemTime::now() + RESET_TOKEN_LIFETIME,
+            })
+            .execute(conn)?;
+        Ok(())
+    })?;
+    // a failing mailer must not answer differently than an unknown address does
+    let sent = mailer.send(Mail {
+        to: user.email.clone(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your account.\n\n\
+             Open the link below within the next hour to choose a new one:\n\
+             {}/reset-password?token={}\n\n\
+             If this wasn't you, you can ignore this mail.\n",
+            FRONTEND_URL.trim_end_matches('/'),
+            token
+        ),
+    });
+    if let Err(err) = sent {
+        error!(
+            "Could not send the password reset mail to user {}: {}",
+            user.id, err
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetData {
+    pub token: String,
+    pub new_password: String,
+}
+
+pub async fn reset_password(
+    reset_data: web::Json<ResetData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let reset_data = reset_data.into_inner();
+    let res = web::block(move || reset_password_query(reset_data, pool)).await;
+
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn reset_password_query(reset_data: ResetData, pool: web::Data<Pool>) -> Result<(), ServiceError> {
+    use crate::schema::{
+        password_reset_tokens::dsl::{expires_at, password_reset_tokens, token_hash, used_at},
+        users::dsl::{failed_logins, hash, locked_until, users},
+    };
+
+    validate_password(&reset_data.new_password)?;
+    let conn = &pool.get().unwrap();
+    conn.transaction(|| {
+        // marking the token as used in the same statement that finds it keeps it single-use
+        let now = SystemTime::now();
+        let reset_token = diesel::update(
+            password_reset_tokens
+                .filter(token_hash.eq(hash_token(&reset_data.token)))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(now)),
+        )
+        .set(used_at.eq(now))
+        .get_result::<PasswordResetToken>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::BadRequest("Invalid or expired reset token".to_string()))?;
+        // the reset proves access to the mailbox just like the unlock link, so it also unlocks
+        diesel::update(users.find(reset_token.user_id))
+            .set((
+                hash.eq(hash_password(&reset_data.new_password)?),
+                failed_logins.eq(0),
+                locked_until.eq(None::<SystemTime>),
+            ))
+            .execute(conn)?;
+        revoke_sessions(reset_token.user_id, None, conn)?;
+        Ok(())
+    })
+}
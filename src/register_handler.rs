@@ -1,18 +1,28 @@
+use std::time::{Duration, SystemTime};
+
 use actix_web::{error::BlockingError, web, HttpResponse};
 use diesel::prelude::*;
+use log::error;
+use serde::Deserialize;
 
 use crate::{
     auth_handler::AuthData,
     errors::ServiceError,
-    models::{NewUser, Pool, SlimUser, User},
-    utils::hash_password,
+    mailer::{Mail, Mailer},
+    models::{EmailVerificationToken, NewEmailVerificationToken, NewUser, Pool, SlimUser, User},
+    utils::{
+        hash_password, hash_token, new_token, validate_email, validate_password, FRONTEND_URL,
+    },
 };
 
+const VERIFICATION_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub async fn register(
     user_data: web::Json<AuthData>,
     pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
 ) -> Result<HttpResponse, ServiceError> {
-    let res = web::block(move || query(user_data.into_inner(), pool)).await;
+    let res = web::block(move || query(user_data.into_inner(), pool, mailer)).await;
     match res {
         Ok(user) => Ok(HttpResponse::Ok().json(&user)),
         Err(err) => match err {
@@ -22,14 +32,145 @@ pub async fn register(
     }
 }
 
-fn query(user_data: AuthData, pool: web::Data<Pool>) -> Result<SlimUser, ServiceError> {
+fn query(
+    user_data: AuthData,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<SlimUser, ServiceError> {
     use crate::schema::users::dsl::users;
+    validate_email(&user_data.email)?;
+    validate_password(&user_data.password)?;
     let conn = &pool.get().unwrap();
     let hashed_password = hash_password(&user_data.password)?;
     let new_user = NewUser::from_details(user_data.email, hashed_password);
     let inserted_user: User = diesel::insert_into(users)
         .values(&new_user)
         .get_result(conn)?;
-    dbg!(&inserted_user);
+    // the account is kept even if the mail doesn't go out, the user can ask for another one
+    if let Err(err) = send_verification(&inserted_user, &**mailer, conn) {
+        error!(
+            "Could not send the verification mail to user {}: {}",
+            inserted_user.id, err
+        );
+    }
     Ok(inserted_user.into())
 }
+
+fn send_verification(
+    user: &User,
+    mailer: &dyn Mailer,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::email_verification_tokens::dsl::email_verification_tokens;
+
+    // only the latest link works
+    diesel::delete(EmailVerificationToken::belonging_to(user)).execute(conn)?;
+    let token = new_token();
+    diesel::insert_into(email_verification_tokens)
+        .values(NewEmailVerificationToken {
+            user_id: user.id,
+            token_hash: &hash_token(&token),
+            expires_at: SystemTime::now() + VERIFICATION_TOKEN_LIFETIME,
+        })
+        .execute(conn)?;
+    mailer.send(Mail {
+        to: user.email.clone(),
+        subject: "Confirm your email address".to_string(),
+        body: format!(
+            "Welcome to Moodtracker!\n\n\
+             Open the link below within the next day to confirm your email address:\n\
+             {}/verify-email?token={}\n",
+            FRONTEND_URL.trim_end_matches('/'),
+            token
+        ),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyData {
+    pub token: String,
+}
+
+pub async fn verify_email(
+    verify_data: web::Json<VerifyData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let verify_data = verify_data.into_inner();
+    let res = web::block(move || verify_email_query(verify_data, pool)).await;
+    match res {
+        Ok(user) => Ok(HttpResponse::Ok().json(&user)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn verify_email_query(
+    verify_data: VerifyData,
+    pool: web::Data<Pool>,
+) -> Result<SlimUser, ServiceError> {
+    use crate::schema::{
+        email_verification_tokens::dsl::{email_verification_tokens, expires_at, token_hash},
+        users::dsl::{users, verified_at},
+    };
+
+    let conn = &pool.get().unwrap();
+    conn.transaction(|| {
+        let token = diesel::delete(
+            email_verification_tokens
+                .filter(token_hash.eq(hash_token(&verify_data.token)))
+                .filter(expires_at.gt(SystemTime::now())),
+        )
+        .get_result::<EmailVerificationToken>(conn)
+        .optional()?
+        .ok_or_else(|| {
+            ServiceError::BadRequest("Invalid or expired verification token".to_string())
+        })?;
+        let user = diesel::update(users.find(token.user_id))
+            .set(verified_at.eq(SystemTime::now()))
+            .get_result::<User>(conn)?;
+        Ok(user.into())
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendData {
+    pub email: String,
+}
+
+// like the password reset this always answers 200, so it doesn't reveal which addresses exist
+pub async fn resend_verification(
+    resend_data: web::Json<ResendData>,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<HttpResponse, ServiceError> {
+    let resend_data = resend_data.into_inner();
+    let res = web::block(move || resend_verification_query(resend_data, pool, mailer)).await;
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn resend_verification_query(
+    resend_data: ResendData,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<(), ServiceError> {
+    use crate::schema::users::dsl::{email, users, verified_at};
+
+    let conn = &pool.get().unwrap();
+    let user = users
+        .filter(email.eq(&resend_data.email))
+        .filter(verified_at.is_null())
+        .get_result::<User>(conn)
+        .optional()?;
+    match user {
+        Some(user) => conn.transaction(|| send_verification(&user, &**mailer, conn)),
+        None => Ok(()),
+    }
+}
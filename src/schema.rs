@@ -1,13 +1,63 @@
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    account_unlock_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
     activities (id) {
         id -> Int4,
         user_id -> Int4,
         name -> Text,
-        icon -> Bpchar,
+        icon -> Text,
+        archived -> Bool,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    email_verification_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
     entry_activities (id) {
         id -> Int4,
         entry_id -> Int4,
@@ -16,56 +66,204 @@ table! {
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    entry_image_variants (id) {
+        id -> Int4,
+        image_id -> Int4,
+        name -> Text,
+        width -> Int4,
+        height -> Int4,
+        url -> Text,
+        storage_key -> Text,
+        content_type -> Text,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
     entry_images (id) {
         id -> Int4,
         user_id -> Int4,
         entry_id -> Int4,
         image_url -> Text,
+        storage_key -> Nullable<Text>,
+        content_type -> Nullable<Text>,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
     entrys (id) {
         id -> Int4,
         user_id -> Int4,
         mood_id -> Int4,
         desc -> Nullable<Text>,
         created_at -> Timestamp,
+        search_language -> Regconfig,
+        search_vector -> Tsvector,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    login_attempts (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        email -> Text,
+        method -> Text,
+        outcome -> Text,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        created_at -> Timestamp,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
     moods (id) {
         id -> Int4,
         user_id -> Int4,
         name -> Text,
         value -> Int4,
-        icon -> Bpchar,
+        icon -> Text,
+        archived -> Bool,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    passkey_challenges (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Text,
+        kind -> Text,
+        state -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    passkeys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        credential_id -> Text,
+        passkey -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    password_reset_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
+    sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Text,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        expires_at -> Timestamp,
+        two_factor_pending -> Bool,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::*;
+
     users (id) {
         id -> Int4,
         email -> Varchar,
         hash -> Varchar,
+        search_language -> Text,
+        verified_at -> Nullable<Timestamp>,
+        totp_secret -> Nullable<Text>,
+        totp_enabled_at -> Nullable<Timestamp>,
+        totp_last_step -> Nullable<Int8>,
+        failed_logins -> Int4,
+        locked_until -> Nullable<Timestamp>,
     }
 }
 
+joinable!(account_unlock_tokens -> users (user_id));
 joinable!(activities -> users (user_id));
+joinable!(api_tokens -> users (user_id));
+joinable!(email_verification_tokens -> users (user_id));
 joinable!(entry_activities -> activities (activity_id));
 joinable!(entry_activities -> entrys (entry_id));
+joinable!(entry_image_variants -> entry_images (image_id));
 joinable!(entry_images -> entrys (entry_id));
 joinable!(entry_images -> users (user_id));
 joinable!(entrys -> moods (mood_id));
 joinable!(entrys -> users (user_id));
+joinable!(login_attempts -> users (user_id));
 joinable!(moods -> users (user_id));
+joinable!(passkey_challenges -> users (user_id));
+joinable!(passkeys -> users (user_id));
+joinable!(password_reset_tokens -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(sessions -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    account_unlock_tokens,
     activities,
+    api_tokens,
+    email_verification_tokens,
     entry_activities,
+    entry_image_variants,
     entry_images,
     entrys,
+    login_attempts,
     moods,
+    passkey_challenges,
+    passkeys,
+    password_reset_tokens,
+    recovery_codes,
+    sessions,
     users,
 );
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{Float4, Int4, Int8, Text},
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth_handler::{ApiUser, LoggedUser, Scope},
+    entry_handler::{load_big_entries, BigEntry},
+    errors::ServiceError,
+    models::{Entry, Pool, ENTRY_COLUMNS},
+};
+
+const DEFAULT_RESULT_COUNT: i64 = 20;
+const MAX_RESULT_COUNT: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub entry: BigEntry,
+    pub rank: f32,
+    // the matching part of the description as HTML: the text is escaped and hits are
+    // wrapped in <mark> tags
+    pub snippet: String,
+}
+
+#[derive(Debug, QueryableByName)]
+struct SearchHit {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Float4"]
+    rank: f32,
+    #[sql_type = "Text"]
+    snippet: String,
+}
+
+pub async fn search_entrys(
+    api_user: ApiUser,
+    search_query: web::Query<SearchQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    info!("Request to search entries by {}", logged_user.email);
+    let search_query = search_query.into_inner();
+    let res = web::block(move || search_entrys_query(logged_user, search_query, pool)).await;
+
+    match res {
+        Ok(results) => Ok(HttpResponse::Ok().json(&results)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn search_entrys_query(
+    logged_user: LoggedUser,
+    search_query: SearchQuery,
+    pool: web::Data<Pool>,
+) -> Result<Vec<SearchResult>, ServiceError> {
+    use crate::schema::entrys::dsl::{entrys, id};
+
+    let limit = search_query
+        .limit
+        .unwrap_or(DEFAULT_RESULT_COUNT)
+        .clamp(1, MAX_RESULT_COUNT);
+
+    let conn = &pool.get().unwrap();
+    // the query is parsed with the same language the user's entries are indexed in, the
+    // description is escaped before the <mark> tags are added so it can't smuggle in markup
+    let hits = sql_query(
+        r#"SELECT "entrys"."id",
+                ts_rank("entrys"."search_vector", "query"."q") AS "rank",
+                ts_headline("entrys"."search_language",
+                    replace(replace(replace("entrys"."desc", '&', '&amp;'), '<', '&lt;'),
+                        '>', '&gt;'),
+                    "query"."q", 'StartSel=<mark>, StopSel=</mark>') AS "snippet"
+            FROM "entrys",
+                websearch_to_tsquery(
+                    (SELECT "search_language"::regconfig FROM "users" WHERE "id" = $1), $2
+                ) AS "query"("q")
+            WHERE "entrys"."user_id" = $1 AND "entrys"."search_vector" @@ "query"."q"
+            ORDER BY "rank" DESC, "entrys"."created_at" DESC
+            LIMIT $3"#,
+    )
+    .bind::<Int4, _>(logged_user.id)
+    .bind::<Text, _>(&search_query.q)
+    .bind::<Int8, _>(limit)
+    .load::<SearchHit>(conn)?;
+
+    let hit_ids: Vec<i32> = hits.iter().map(|hit| hit.id).collect();
+    let entry_vec = entrys
+        .filter(id.eq_any(hit_ids))
+        .select(ENTRY_COLUMNS)
+        .get_results::<Entry>(conn)?;
+    let mut entry_map: HashMap<i32, BigEntry> = load_big_entries(entry_vec, conn)?
+        .into_iter()
+        .map(|entry| (entry.id, entry))
+        .collect();
+
+    Ok(hits
+        .into_iter()
+        .filter_map(|hit| {
+            entry_map.remove(&hit.id).map(|entry| SearchResult {
+                entry,
+                rank: hit.rank,
+                snippet: hit.snippet,
+            })
+        })
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LanguageData {
+    pub language: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LanguageSettings {
+    pub language: String,
+    pub available: Vec<String>,
+}
+
+#[derive(Debug, QueryableByName)]
+struct TextSearchConfig {
+    #[sql_type = "Text"]
+    name: String,
+}
+
+pub async fn get_search_language(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let res = web::block(move || get_search_language_query(logged_user, pool)).await;
+
+    match res {
+        Ok(settings) => Ok(HttpResponse::Ok().json(&settings)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn get_search_language_query(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<LanguageSettings, ServiceError> {
+    use crate::schema::users::dsl::{search_language, users};
+
+    let conn = &pool.get().unwrap();
+    let language = users
+        .find(logged_user.id)
+        .select(search_language)
+        .get_result::<String>(conn)?;
+    Ok(LanguageSettings {
+        language,
+        available: available_languages(conn)?,
+    })
+}
+
+pub async fn set_search_language(
+    logged_user: LoggedUser,
+    language_data: web::Json<LanguageData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    info!("Request to change search language by {}", logged_user.email);
+    let language_data = language_data.into_inner();
+    let res = web::block(move || set_search_language_query(logged_user, language_data, pool)).await;
+
+    match res {
+        Ok(settings) => Ok(HttpResponse::Ok().json(&settings)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn set_search_language_query(
+    logged_user: LoggedUser,
+    language_data: LanguageData,
+    pool: web::Data<Pool>,
+) -> Result<LanguageSettings, ServiceError> {
+    use crate::schema::users::dsl::{search_language, users};
+
+    let conn = &pool.get().unwrap();
+    let available = available_languages(conn)?;
+    if !available.contains(&language_data.language) {
+        return Err(ServiceError::BadRequest(format!(
+            "Unknown search language: {}",
+            language_data.language
+        )));
+    }
+    // a trigger re-indexes the user's entries in the new language
+    let language = diesel::update(users.find(logged_user.id))
+        .set(search_language.eq(&language_data.language))
+        .returning(search_language)
+        .get_result::<String>(conn)?;
+    Ok(LanguageSettings {
+        language,
+        available,
+    })
+}
+
+fn available_languages(conn: &PgConnection) -> Result<Vec<String>, ServiceError> {
+    let configs = sql_query(r#"SELECT "cfgname"::text AS "name" FROM "pg_ts_config" ORDER BY 1"#)
+        .load::<TextSearchConfig>(conn)?;
+    Ok(configs.into_iter().map(|config| config.name).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        entry_handler::{create_entry_query, EntryData},
+        test_utils::{create_mood, pool, TestUser},
+    };
+
+    #[test]
+    fn snippets_escape_the_description() {
+        let pool = web::Data::new(pool());
+        let test_user = TestUser::create(&pool);
+        let mood = create_mood(test_user.user.id, 3, &pool.get().unwrap());
+        create_entry_query(
+            test_user.logged_user(),
+            EntryData {
+                mood_id: mood.id,
+                desc: Some(
+                    "<img src=x onerror=alert(1)> went walking & <b>talking</b>".to_string(),
+                ),
+                created_at: None,
+                activity_ids: Vec::new(),
+                image_urls: Vec::new(),
+            },
+            pool.clone(),
+        )
+        .unwrap();
+
+        let results = search_entrys_query(
+            test_user.logged_user(),
+            SearchQuery {
+                q: "walking".to_string(),
+                limit: None,
+            },
+            pool,
+        )
+        .unwrap();
+
+        assert_eq!(results.len(), 1);
+        let snippet = &results[0].snippet;
+        assert!(snippet.contains("<mark>walking</mark> &amp; &lt;b&gt;talking&lt;/b&gt;"));
+        assert!(!snippet.contains("<img") && !snippet.contains("<b>"));
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use actix_identity::Identity;
+use actix_web::{error::BlockingError, web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    auth_handler::CurrentSession,
+    errors::ServiceError,
+    models::{NewSession, Pool, Session, User},
+    utils::{hash_token, new_token},
+};
+
+// also the max_age of the auth cookie
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+// how long a session may wait for the second factor after the password was checked
+const TWO_FACTOR_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+lazy_static::lazy_static! {
+    // only behind a reverse proxy that sets them may Forwarded / X-Forwarded-For be believed,
+    // otherwise every client could pick its own address
+    pub static ref TRUST_PROXY: bool = match std::env::var("TRUST_PROXY").as_deref() {
+        Ok("true") => true,
+        Ok("false") | Err(_) => false,
+        Ok(other) => panic!("TRUST_PROXY must be true or false, not {}", other),
+    };
+}
+
+// what we remember about the device a session was started from
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        ClientInfo {
+            user_agent: req
+                .headers()
+                .get("user-agent")
+                .and_then(|value| value.to_str().ok())
+                .map(String::from),
+            ip: if *TRUST_PROXY {
+                // forwarded addresses usually come without a port
+                req.connection_info().realip_remote_addr().map(|addr| {
+                    addr.parse::<SocketAddr>()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_else(|_| addr.to_string())
+                })
+            } else {
+                req.peer_addr().map(|addr| addr.ip().to_string())
+            },
+        }
+    }
+}
+
+// returns the token for the auth cookie, the table only keeps its hash;
+// a `two_factor_pending` session can't be used until `complete_two_factor` was called
+pub fn create_session(
+    user_id: i32,
+    client: &ClientInfo,
+    two_factor_pending: bool,
+    conn: &PgConnection,
+) -> Result<String, ServiceError> {
+    use crate::schema::sessions::dsl::{expires_at, sessions, user_id as sessions_user_id};
+
+    let now = SystemTime::now();
+    diesel::delete(
+        sessions
+            .filter(sessions_user_id.eq(user_id))
+            .filter(expires_at.le(now)),
+    )
+    .execute(conn)?;
+    let token = new_token();
+    diesel::insert_into(sessions)
+        .values(NewSession {
+            user_id,
+            token_hash: &hash_token(&token),
+            user_agent: client.user_agent.as_deref(),
+            ip: client.ip.as_deref(),
+            expires_at: now
+                + if two_factor_pending {
+                    TWO_FACTOR_LIFETIME
+                } else {
+                    SESSION_LIFETIME
+                },
+            two_factor_pending,
+        })
+        .execute(conn)?;
+    Ok(token)
+}
+
+// finds the session still waiting for the second factor behind a cookie token
+pub fn find_pending_session(token: &str, conn: &PgConnection) -> Result<Session, ServiceError> {
+    use crate::schema::sessions::dsl::{expires_at, sessions, token_hash, two_factor_pending};
+
+    sessions
+        .filter(token_hash.eq(hash_token(token)))
+        .filter(two_factor_pending.eq(true))
+        .filter(expires_at.gt(SystemTime::now()))
+        .get_result::<Session>(conn)
+        .optional()?
+        .ok_or(ServiceError::Unauthorized)
+}
+
+pub fn complete_two_factor(session: &Session, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::sessions::dsl::{expires_at, last_seen_at, two_factor_pending};
+
+    let now = SystemTime::now();
+    diesel::update(session)
+        .set((
+            two_factor_pending.eq(false),
+            last_seen_at.eq(now),
+            expires_at.eq(now + SESSION_LIFETIME),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+// looks up the session behind a cookie token and marks it as seen
+pub fn touch_session(token: &str, conn: &PgConnection) -> Result<(Session, User), ServiceError> {
+    use crate::schema::{
+        sessions::dsl::{expires_at, last_seen_at, sessions, token_hash, two_factor_pending},
+        users::dsl::users,
+    };
+
+    let now = SystemTime::now();
+    let session = diesel::update(
+        sessions
+            .filter(token_hash.eq(hash_token(token)))
+            .filter(two_factor_pending.eq(false))
+            .filter(expires_at.gt(now)),
+    )
+    .set(last_seen_at.eq(now))
+    .get_result::<Session>(conn)
+    .optional()?
+    .ok_or(ServiceError::Unauthorized)?;
+    let user = users.find(session.user_id).get_result::<User>(conn)?;
+    Ok((session, user))
+}
+
+pub fn delete_session(token: &str, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::sessions::dsl::{sessions, token_hash};
+
+    diesel::delete(sessions.filter(token_hash.eq(hash_token(token)))).execute(conn)?;
+    Ok(())
+}
+
+// ends every session of the user, apart from `keep` if given
+pub fn revoke_sessions(
+    user_id: i32,
+    keep: Option<i32>,
+    conn: &PgConnection,
+) -> Result<usize, ServiceError> {
+    use crate::schema::sessions::dsl::{id, sessions, user_id as sessions_user_id};
+
+    let revoked = match keep {
+        Some(keep) => diesel::delete(
+            sessions
+                .filter(sessions_user_id.eq(user_id))
+                .filter(id.ne(keep)),
+        )
+        .execute(conn)?,
+        None => diesel::delete(sessions.filter(sessions_user_id.eq(user_id))).execute(conn)?,
+    };
+    Ok(revoked)
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: Session,
+    // whether this is the session making the request
+    pub current: bool,
+}
+
+pub async fn get_sessions(
+    current: CurrentSession,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let res = web::block(move || get_sessions_query(current, pool)).await;
+
+    match res {
+        Ok(session_vec) => Ok(HttpResponse::Ok().json(&session_vec)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn get_sessions_query(
+    current: CurrentSession,
+    pool: web::Data<Pool>,
+) -> Result<Vec<SessionInfo>, ServiceError> {
+    use crate::schema::sessions::dsl::{
+        expires_at, last_seen_at, sessions, two_factor_pending, user_id,
+    };
+
+    let conn = &pool.get().unwrap();
+    let session_vec = sessions
+        .filter(user_id.eq(current.user.id))
+        .filter(two_factor_pending.eq(false))
+        .filter(expires_at.gt(SystemTime::now()))
+        .order(last_seen_at.desc())
+        .get_results::<Session>(conn)?;
+    Ok(session_vec
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: session.id == current.session.id,
+            session,
+        })
+        .collect())
+}
+
+pub async fn revoke_session(
+    current: CurrentSession,
+    session_id: web::Path<i32>,
+    id: Identity,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let session_id = session_id.into_inner();
+    let is_current = session_id == current.session.id;
+    let res = web::block(move || revoke_session_query(session_id, current, pool)).await;
+
+    match res {
+        Ok(_) => {
+            if is_current {
+                id.forget();
+            }
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn revoke_session_query(
+    session_id: i32,
+    current: CurrentSession,
+    pool: web::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::sessions::dsl::{sessions, user_id};
+
+    let conn = &pool.get().unwrap();
+    let deleted = diesel::delete(
+        sessions
+            .find(session_id)
+            .filter(user_id.eq(current.user.id)),
+    )
+    .execute(conn)?;
+    if deleted == 0 {
+        return Err(ServiceError::NotFound);
+    }
+    Ok(())
+}
+
+// "log out everywhere", including the session making the request
+pub async fn revoke_all_sessions(
+    current: CurrentSession,
+    id: Identity,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let res =
+        web::block(move || revoke_sessions(current.user.id, None, &pool.get().unwrap())).await;
+
+    match res {
+        Ok(_) => {
+            id.forget();
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
@@ -0,0 +1,9 @@
+// postgres types used by schema.rs that diesel does not ship itself
+
+#[derive(SqlType)]
+#[postgres(oid = "3734", array_oid = "3735")]
+pub struct Regconfig;
+
+#[derive(SqlType)]
+#[postgres(oid = "3614", array_oid = "3643")]
+pub struct Tsvector;
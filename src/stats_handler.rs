@@ -0,0 +1,1050 @@
+use std::collections::HashMap;
+
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::{Datelike, NaiveDate};
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{Bool, Date, Float8, Int4, Int8, Nullable, Text},
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth_handler::{ApiUser, LoggedUser, Scope},
+    errors::ServiceError,
+    models::{Activity, Pool, User},
+};
+
+const DEFAULT_TIMEZONE: &str = "UTC";
+
+#[derive(Debug, QueryableByName)]
+struct TimezoneCheck {
+    #[sql_type = "Bool"]
+    known: bool,
+}
+
+// entry times are stored in UTC, every statistic is about the days of the given timezone,
+// an IANA name like `Europe/Berlin`
+fn check_timezone(tz: Option<String>, conn: &PgConnection) -> Result<String, ServiceError> {
+    let tz = tz.unwrap_or_else(|| DEFAULT_TIMEZONE.to_string());
+    let check = sql_query(
+        r#"SELECT EXISTS (SELECT 1 FROM "pg_timezone_names" WHERE "name" = $1) AS "known""#,
+    )
+    .bind::<Text, _>(&tz)
+    .get_result::<TimezoneCheck>(conn)?;
+    if !check.known {
+        return Err(ServiceError::BadRequest(format!(
+            "Unknown timezone: {}",
+            tz
+        )));
+    }
+    Ok(tz)
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Day,
+    // weeks start on monday
+    Week,
+    Month,
+}
+
+impl Bucket {
+    // the field name date_trunc expects
+    fn as_str(self) -> &'static str {
+        match self {
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoodStatsQuery {
+    pub bucket: Option<Bucket>,
+    // both bounds are inclusive calendar days
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub tz: Option<String>,
+}
+
+// buckets without entries are left out
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct MoodBucket {
+    // first day of the bucket
+    #[sql_type = "Date"]
+    pub start: NaiveDate,
+    #[sql_type = "Int8"]
+    pub count: i64,
+    #[sql_type = "Float8"]
+    pub mean: f64,
+    #[sql_type = "Int4"]
+    pub min: i32,
+    #[sql_type = "Int4"]
+    pub max: i32,
+    // population standard deviation, 0 for a single entry
+    #[sql_type = "Float8"]
+    pub std_dev: f64,
+}
+
+pub async fn get_mood_stats(
+    api_user: ApiUser,
+    stats_query: web::Query<MoodStatsQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    info!("Request to get mood statistics by {}", logged_user.email);
+    let stats_query = stats_query.into_inner();
+    let res = web::block(move || get_mood_stats_query(logged_user, stats_query, pool)).await;
+
+    match res {
+        Ok(buckets) => Ok(HttpResponse::Ok().json(&buckets)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn get_mood_stats_query(
+    logged_user: LoggedUser,
+    stats_query: MoodStatsQuery,
+    pool: web::Data<Pool>,
+) -> Result<Vec<MoodBucket>, ServiceError> {
+    let conn = &pool.get().unwrap();
+    let tz = check_timezone(stats_query.tz, conn)?;
+    let bucket = stats_query.bucket.unwrap_or(Bucket::Day);
+
+    let buckets = sql_query(
+        r#"SELECT date_trunc($2, "local"."created_at")::date AS "start",
+                count(*) AS "count",
+                avg("moods"."value")::float8 AS "mean",
+                min("moods"."value") AS "min",
+                max("moods"."value") AS "max",
+                stddev_pop("moods"."value")::float8 AS "std_dev"
+            FROM "entrys"
+                INNER JOIN "moods" ON "moods"."id" = "entrys"."mood_id",
+                LATERAL (
+                    SELECT "entrys"."created_at" AT TIME ZONE 'UTC' AT TIME ZONE $3
+                ) AS "local"("created_at")
+            WHERE "entrys"."user_id" = $1
+                AND ($4::date IS NULL OR "local"."created_at" >= $4::date)
+                AND ($5::date IS NULL OR "local"."created_at" < $5::date + 1)
+            GROUP BY 1
+            ORDER BY 1"#,
+    )
+    .bind::<Int4, _>(logged_user.id)
+    .bind::<Text, _>(bucket.as_str())
+    .bind::<Text, _>(&tz)
+    .bind::<Nullable<Date>, _>(stats_query.from)
+    .bind::<Nullable<Date>, _>(stats_query.to)
+    .load::<MoodBucket>(conn)?;
+    Ok(buckets)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImpactQuery {
+    pub tz: Option<String>,
+}
+
+// count, sum and sum of squares of some mood values, all a mean and variance need
+#[derive(Debug, Clone, Copy, Default, QueryableByName)]
+struct MoodSums {
+    #[sql_type = "Int8"]
+    count: i64,
+    #[sql_type = "Float8"]
+    sum: f64,
+    #[sql_type = "Float8"]
+    sum_sq: f64,
+}
+
+impl MoodSums {
+    fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.sum / self.count as f64)
+    }
+
+    // sample variance
+    fn variance(&self) -> Option<f64> {
+        if self.count < 2 {
+            return None;
+        }
+        let n = self.count as f64;
+        Some(((self.sum_sq - self.sum * self.sum / n) / (n - 1.0)).max(0.0))
+    }
+
+    fn without(&self, part: &MoodSums) -> MoodSums {
+        MoodSums {
+            count: self.count - part.count,
+            sum: self.sum - part.sum,
+            sum_sq: self.sum_sq - part.sum_sq,
+        }
+    }
+}
+
+// the sums of one activity, the row with no activity holds the sums over everything
+#[derive(Debug, QueryableByName)]
+struct ActivitySums {
+    #[sql_type = "Nullable<Int4>"]
+    activity_id: Option<i32>,
+    #[diesel(embed)]
+    sums: MoodSums,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MoodGroup {
+    pub count: i64,
+    pub mean: Option<f64>,
+}
+
+impl From<MoodSums> for MoodGroup {
+    fn from(sums: MoodSums) -> Self {
+        MoodGroup {
+            count: sums.count,
+            mean: sums.mean(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MoodComparison {
+    pub with: MoodGroup,
+    pub without: MoodGroup,
+    // mean with minus mean without
+    pub difference: Option<f64>,
+    // 95% confidence interval of the difference (Welch's t-test),
+    // null until both groups have at least two values
+    pub ci_low: Option<f64>,
+    pub ci_high: Option<f64>,
+    // whether the interval excludes 0
+    pub significant: bool,
+}
+
+// two-sided 95% critical values of the t distribution for 1 to 30 degrees of freedom
+const T_CRITICAL: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+// rounds the degrees of freedom down, which only ever widens the interval
+fn t_critical(df: f64) -> f64 {
+    match df.floor() as usize {
+        0 => T_CRITICAL[0],
+        df @ 1..=30 => T_CRITICAL[df - 1],
+        31..=39 => T_CRITICAL[29],
+        40..=59 => 2.021,
+        60..=119 => 2.000,
+        _ => 1.980,
+    }
+}
+
+fn compare(with: MoodSums, without: MoodSums) -> MoodComparison {
+    let difference = match (with.mean(), without.mean()) {
+        (Some(with_mean), Some(without_mean)) => Some(with_mean - without_mean),
+        _ => None,
+    };
+    let interval = match (difference, with.variance(), without.variance()) {
+        (Some(difference), Some(with_var), Some(without_var)) => {
+            let with_part = with_var / with.count as f64;
+            let without_part = without_var / without.count as f64;
+            let std_err = (with_part + without_part).sqrt();
+            // Welch–Satterthwaite, only defined if either group varies at all
+            let df_divisor = with_part * with_part / (with.count - 1) as f64
+                + without_part * without_part / (without.count - 1) as f64;
+            let margin = if df_divisor > 0.0 {
+                let df = (with_part + without_part).powi(2) / df_divisor;
+                t_critical(df) * std_err
+            } else {
+                0.0
+            };
+            Some((difference - margin, difference + margin))
+        }
+        _ => None,
+    };
+    MoodComparison {
+        with: with.into(),
+        without: without.into(),
+        difference,
+        ci_low: interval.map(|(low, _)| low),
+        ci_high: interval.map(|(_, high)| high),
+        significant: interval.is_some_and(|(low, high)| low > 0.0 || high < 0.0),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActivityImpact {
+    pub activity: Activity,
+    // entries with the activity against entries without it
+    pub same_day: MoodComparison,
+    // the mood of the following day, after days with the activity against days without it,
+    // only days that were logged and followed by a logged day count
+    pub next_day: MoodComparison,
+}
+
+pub async fn get_activity_impact(
+    api_user: ApiUser,
+    impact_query: web::Query<ImpactQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    info!("Request to get activity impact by {}", logged_user.email);
+    let impact_query = impact_query.into_inner();
+    let res = web::block(move || get_activity_impact_query(logged_user, impact_query, pool)).await;
+
+    match res {
+        Ok(impacts) => Ok(HttpResponse::Ok().json(&impacts)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+// splits the rows into the sums per activity and the sums over everything
+fn split_sums(rows: Vec<ActivitySums>) -> (HashMap<i32, MoodSums>, MoodSums) {
+    let mut total = MoodSums::default();
+    let mut per_activity = HashMap::new();
+    for row in rows {
+        match row.activity_id {
+            Some(activity_id) => {
+                per_activity.insert(activity_id, row.sums);
+            }
+            None => total = row.sums,
+        }
+    }
+    (per_activity, total)
+}
+
+// ordered by how much better the days with the activity are, archived activities included
+fn get_activity_impact_query(
+    logged_user: LoggedUser,
+    impact_query: ImpactQuery,
+    pool: web::Data<Pool>,
+) -> Result<Vec<ActivityImpact>, ServiceError> {
+    use crate::schema::{activities::dsl::id, users::dsl::users};
+
+    let conn = &pool.get().unwrap();
+    let tz = check_timezone(impact_query.tz, conn)?;
+    let user = users.find(logged_user.id).get_result::<User>(conn)?;
+    let activity_vec = Activity::belonging_to(&user)
+        .order(id)
+        .get_results::<Activity>(conn)?;
+
+    // an entry counts once per activity, like it does once in the total
+    let same_day = sql_query(
+        r#"SELECT "entry_links"."activity_id",
+                count(*) AS "count",
+                sum("moods"."value")::float8 AS "sum",
+                sum("moods"."value" * "moods"."value")::float8 AS "sum_sq"
+            FROM "entrys"
+                INNER JOIN "moods" ON "moods"."id" = "entrys"."mood_id"
+                INNER JOIN (
+                    SELECT DISTINCT "entry_id", "activity_id" FROM "entry_activities"
+                ) AS "entry_links" ON "entry_links"."entry_id" = "entrys"."id"
+            WHERE "entrys"."user_id" = $1
+            GROUP BY 1
+        UNION ALL
+        SELECT NULL,
+                count(*),
+                coalesce(sum("moods"."value"), 0)::float8,
+                coalesce(sum("moods"."value" * "moods"."value"), 0)::float8
+            FROM "entrys"
+                INNER JOIN "moods" ON "moods"."id" = "entrys"."mood_id"
+            WHERE "entrys"."user_id" = $1"#,
+    )
+    .bind::<Int4, _>(user.id)
+    .load::<ActivitySums>(conn)?;
+
+    // a day counts once per activity no matter how many of its entries have it,
+    // and contributes the average mood of the day after
+    let next_day = sql_query(
+        r#"WITH "entry_days" AS (
+                SELECT "entrys"."id",
+                        ("entrys"."created_at" AT TIME ZONE 'UTC' AT TIME ZONE $2)::date AS "day",
+                        "moods"."value"
+                    FROM "entrys"
+                        INNER JOIN "moods" ON "moods"."id" = "entrys"."mood_id"
+                    WHERE "entrys"."user_id" = $1
+            ), "pairs" AS (
+                SELECT "today"."day", avg("tomorrow"."value")::float8 AS "mood"
+                    FROM (SELECT DISTINCT "day" FROM "entry_days") AS "today"
+                        INNER JOIN "entry_days" AS "tomorrow"
+                            ON "tomorrow"."day" = "today"."day" + 1
+                    GROUP BY 1
+            )
+        SELECT "day_activities"."activity_id",
+                count(*) AS "count",
+                sum("pairs"."mood") AS "sum",
+                sum("pairs"."mood" * "pairs"."mood") AS "sum_sq"
+            FROM "pairs"
+                INNER JOIN (
+                    SELECT DISTINCT "entry_days"."day", "entry_activities"."activity_id"
+                        FROM "entry_days"
+                            INNER JOIN "entry_activities"
+                                ON "entry_activities"."entry_id" = "entry_days"."id"
+                ) AS "day_activities" ON "day_activities"."day" = "pairs"."day"
+            GROUP BY 1
+        UNION ALL
+        SELECT NULL,
+                count(*),
+                coalesce(sum("mood"), 0),
+                coalesce(sum("mood" * "mood"), 0)
+            FROM "pairs""#,
+    )
+    .bind::<Int4, _>(user.id)
+    .bind::<Text, _>(&tz)
+    .load::<ActivitySums>(conn)?;
+
+    let (same_day, same_day_total) = split_sums(same_day);
+    let (next_day, next_day_total) = split_sums(next_day);
+    let mut impacts: Vec<ActivityImpact> = activity_vec
+        .into_iter()
+        .map(|activity| {
+            let with = same_day.get(&activity.id).copied().unwrap_or_default();
+            let next_with = next_day.get(&activity.id).copied().unwrap_or_default();
+            ActivityImpact {
+                same_day: compare(with, same_day_total.without(&with)),
+                next_day: compare(next_with, next_day_total.without(&next_with)),
+                activity,
+            }
+        })
+        .collect();
+    // activities without a difference yet go last
+    impacts.sort_by(|a, b| {
+        let a = a.same_day.difference.unwrap_or(f64::NEG_INFINITY);
+        let b = b.same_day.difference.unwrap_or(f64::NEG_INFINITY);
+        b.total_cmp(&a)
+    });
+    Ok(impacts)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreakQuery {
+    pub tz: Option<String>,
+}
+
+#[derive(Debug, QueryableByName)]
+struct LoggedDay {
+    #[sql_type = "Date"]
+    day: NaiveDate,
+}
+
+#[derive(Debug, QueryableByName)]
+struct ActivityDay {
+    #[sql_type = "Int4"]
+    activity_id: i32,
+    #[sql_type = "Date"]
+    day: NaiveDate,
+}
+
+// consecutive days with at least one entry, both ends inclusive
+#[derive(Debug, Clone, Serialize)]
+pub struct Streak {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub days: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreakSummary {
+    // null once a whole day went by without an entry
+    pub current: Option<Streak>,
+    // the most recent one if several are equally long
+    pub longest: Option<Streak>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActivityStreaks {
+    pub activity: Activity,
+    #[serde(flatten)]
+    pub streaks: StreakSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonthConsistency {
+    // first day of the month
+    pub month: NaiveDate,
+    pub days_logged: i64,
+    // days of the month so far
+    pub days: i64,
+    pub share: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Streaks {
+    // in the requested timezone, the current streaks are relative to it
+    pub today: NaiveDate,
+    #[serde(flatten)]
+    pub logging: StreakSummary,
+    pub activities: Vec<ActivityStreaks>,
+    // from the month of the first entry up to this one
+    pub months: Vec<MonthConsistency>,
+}
+
+// `days` has to be sorted and free of duplicates
+fn summarize_streaks(days: &[NaiveDate], today: NaiveDate) -> StreakSummary {
+    let mut runs: Vec<Streak> = Vec::new();
+    for &day in days {
+        match runs.last_mut() {
+            Some(run) if run.end.succ_opt() == Some(day) => {
+                run.end = day;
+                run.days += 1;
+            }
+            _ => runs.push(Streak {
+                start: day,
+                end: day,
+                days: 1,
+            }),
+        }
+    }
+    // today's entry may still be missing without breaking the streak
+    let current = runs
+        .last()
+        .filter(|run| Some(run.end) >= today.pred_opt())
+        .cloned();
+    let longest = runs.iter().max_by_key(|run| run.days).cloned();
+    StreakSummary { current, longest }
+}
+
+fn next_month(month: NaiveDate) -> Option<NaiveDate> {
+    match month.month() {
+        12 => NaiveDate::from_ymd_opt(month.year() + 1, 1, 1),
+        _ => NaiveDate::from_ymd_opt(month.year(), month.month() + 1, 1),
+    }
+}
+
+// `days` has to be sorted and free of duplicates
+fn monthly_consistency(days: &[NaiveDate], today: NaiveDate) -> Vec<MonthConsistency> {
+    let mut months = Vec::new();
+    let mut month = match days.first().and_then(|first| first.with_day(1)) {
+        Some(month) => month,
+        None => return months,
+    };
+    while month <= today {
+        let end = match next_month(month) {
+            Some(end) => end,
+            None => break,
+        };
+        let days_logged = days
+            .iter()
+            .filter(|day| **day >= month && **day < end && **day <= today)
+            .count() as i64;
+        let elapsed = if end > today {
+            today.day() as i64
+        } else {
+            (end - month).num_days()
+        };
+        months.push(MonthConsistency {
+            month,
+            days_logged,
+            days: elapsed,
+            share: days_logged as f64 / elapsed as f64,
+        });
+        month = end;
+    }
+    months
+}
+
+pub async fn get_streaks(
+    api_user: ApiUser,
+    streak_query: web::Query<StreakQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let logged_user = api_user.require(Scope::ReadEntries)?;
+    info!("Request to get streaks by {}", logged_user.email);
+    let streak_query = streak_query.into_inner();
+    let res = web::block(move || get_streaks_query(logged_user, streak_query, pool)).await;
+
+    match res {
+        Ok(streaks) => Ok(HttpResponse::Ok().json(&streaks)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+// activities are ordered by their current streak, the longest first
+fn get_streaks_query(
+    logged_user: LoggedUser,
+    streak_query: StreakQuery,
+    pool: web::Data<Pool>,
+) -> Result<Streaks, ServiceError> {
+    use crate::schema::{activities::dsl::id, users::dsl::users};
+
+    let conn = &pool.get().unwrap();
+    let tz = check_timezone(streak_query.tz, conn)?;
+    let user = users.find(logged_user.id).get_result::<User>(conn)?;
+    let activity_vec = Activity::belonging_to(&user)
+        .order(id)
+        .get_results::<Activity>(conn)?;
+
+    let today = sql_query(r#"SELECT (now() AT TIME ZONE $1)::date AS "day""#)
+        .bind::<Text, _>(&tz)
+        .get_result::<LoggedDay>(conn)?
+        .day;
+    let days: Vec<NaiveDate> = sql_query(
+        r#"SELECT DISTINCT ("created_at" AT TIME ZONE 'UTC' AT TIME ZONE $2)::date AS "day"
+            FROM "entrys"
+            WHERE "user_id" = $1
+            ORDER BY 1"#,
+    )
+    .bind::<Int4, _>(user.id)
+    .bind::<Text, _>(&tz)
+    .load::<LoggedDay>(conn)?
+    .into_iter()
+    .map(|logged| logged.day)
+    .collect();
+    let mut activity_days: HashMap<i32, Vec<NaiveDate>> = HashMap::new();
+    for activity_day in sql_query(
+        r#"SELECT DISTINCT "entry_activities"."activity_id",
+                ("entrys"."created_at" AT TIME ZONE 'UTC' AT TIME ZONE $2)::date AS "day"
+            FROM "entrys"
+                INNER JOIN "entry_activities" ON "entry_activities"."entry_id" = "entrys"."id"
+            WHERE "entrys"."user_id" = $1
+            ORDER BY 1, 2"#,
+    )
+    .bind::<Int4, _>(user.id)
+    .bind::<Text, _>(&tz)
+    .load::<ActivityDay>(conn)?
+    {
+        activity_days
+            .entry(activity_day.activity_id)
+            .or_default()
+            .push(activity_day.day);
+    }
+
+    let mut activities: Vec<ActivityStreaks> = activity_vec
+        .into_iter()
+        .map(|activity| {
+            let days = activity_days.remove(&activity.id).unwrap_or_default();
+            ActivityStreaks {
+                streaks: summarize_streaks(&days, today),
+                activity,
+            }
+        })
+        .collect();
+    activities.sort_by_key(|activity| {
+        std::cmp::Reverse(
+            activity
+                .streaks
+                .current
+                .as_ref()
+                .map_or(0, |current| current.days),
+        )
+    });
+
+    Ok(Streaks {
+        today,
+        logging: summarize_streaks(&days, today),
+        activities,
+        months: monthly_consistency(&days, today),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{
+        models::Mood,
+        test_utils::{create_activity, create_entry_at, create_mood, pool, TestUser},
+    };
+
+    fn sums(values: &[f64]) -> MoodSums {
+        MoodSums {
+            count: values.len() as i64,
+            sum: values.iter().sum(),
+            sum_sq: values.iter().map(|value| value * value).sum(),
+        }
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("a value");
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn sums_give_mean_and_sample_variance() {
+        let group = sums(&[1.0, 2.0, 3.0, 4.0]);
+        assert_close(group.mean(), 2.5);
+        assert_close(group.variance(), 5.0 / 3.0);
+        assert_close(sums(&[7.0]).mean(), 7.0);
+        assert_eq!(sums(&[7.0]).variance(), None);
+        assert_eq!(MoodSums::default().mean(), None);
+        // rounding can't make a constant group's variance negative
+        assert_close(sums(&[0.1, 0.1, 0.1]).variance(), 0.0);
+
+        let total = sums(&[1.0, 2.0, 3.0, 4.0, 5.0, 2.0, 4.0, 6.0]);
+        let rest = total.without(&sums(&[1.0, 2.0, 3.0, 4.0, 5.0]));
+        assert_eq!(rest.count, 3);
+        assert_close(rest.mean(), 4.0);
+        assert_close(rest.variance(), 4.0);
+    }
+
+    #[test]
+    fn t_critical_rounds_degrees_of_freedom_down() {
+        assert_eq!(t_critical(0.4), 12.706);
+        assert_eq!(t_critical(1.0), 12.706);
+        assert_eq!(t_critical(1.99), 12.706);
+        assert_eq!(t_critical(3.53), 3.182);
+        assert_eq!(t_critical(30.0), 2.042);
+        assert_eq!(t_critical(39.9), 2.042);
+        assert_eq!(t_critical(40.0), 2.021);
+        assert_eq!(t_critical(60.0), 2.000);
+        assert_eq!(t_critical(198.0), 1.980);
+    }
+
+    #[test]
+    fn compares_with_welchs_interval() {
+        // standard error 1.354006, Welch–Satterthwaite df 3.53, so t = 3.182
+        let comparison = compare(sums(&[1.0, 2.0, 3.0, 4.0, 5.0]), sums(&[2.0, 4.0, 6.0]));
+        assert_close(comparison.difference, -1.0);
+        assert_close(comparison.ci_low, -1.0 - 3.182 * 1.354_006_400_772_66);
+        assert_close(comparison.ci_high, -1.0 + 3.182 * 1.354_006_400_772_66);
+        assert!(!comparison.significant);
+    }
+
+    #[test]
+    fn compares_large_groups_with_the_normal_limit() {
+        let with: Vec<f64> = (0..100)
+            .map(|i| if i % 2 == 0 { 4.0 } else { 6.0 })
+            .collect();
+        let without: Vec<f64> = with.iter().map(|value| value - 2.0).collect();
+        // df 198, so t = 1.980
+        let comparison = compare(sums(&with), sums(&without));
+        assert_close(comparison.difference, 2.0);
+        assert_close(comparison.ci_low, 2.0 - 1.980 * 0.142_133_810_903_740_3);
+        assert_close(comparison.ci_high, 2.0 + 1.980 * 0.142_133_810_903_740_3);
+        assert!(comparison.significant);
+    }
+
+    #[test]
+    fn compares_groups_without_variance() {
+        let comparison = compare(sums(&[4.0, 4.0, 4.0]), sums(&[2.0, 2.0]));
+        assert_close(comparison.difference, 2.0);
+        assert_close(comparison.ci_low, 2.0);
+        assert_close(comparison.ci_high, 2.0);
+        assert!(comparison.significant);
+
+        let comparison = compare(sums(&[3.0, 3.0]), sums(&[3.0, 3.0, 3.0]));
+        assert_close(comparison.difference, 0.0);
+        assert!(!comparison.significant);
+    }
+
+    #[test]
+    fn compares_without_an_interval_below_two_values() {
+        let comparison = compare(sums(&[5.0]), sums(&[1.0, 2.0, 3.0]));
+        assert_close(comparison.difference, 3.0);
+        assert_eq!((comparison.ci_low, comparison.ci_high), (None, None));
+        assert!(!comparison.significant);
+
+        let comparison = compare(MoodSums::default(), sums(&[1.0, 2.0]));
+        assert_eq!(comparison.difference, None);
+        assert_eq!(comparison.with.mean, None);
+        assert!(!comparison.significant);
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> SystemTime {
+        Utc.ymd(year, month, day).and_hms(hour, minute, 0).into()
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    fn streak(streak: &Option<Streak>) -> Option<(NaiveDate, NaiveDate, i64)> {
+        streak
+            .as_ref()
+            .map(|streak| (streak.start, streak.end, streak.days))
+    }
+
+    #[test]
+    fn streaks_break_on_a_missing_day() {
+        let days = [
+            date(2026, 1, 1),
+            date(2026, 1, 2),
+            date(2026, 1, 4),
+            date(2026, 1, 5),
+            date(2026, 1, 6),
+        ];
+        let summary = summarize_streaks(&days, date(2026, 1, 6));
+        assert_eq!(
+            streak(&summary.current),
+            Some((date(2026, 1, 4), date(2026, 1, 6), 3))
+        );
+        assert_eq!(
+            streak(&summary.longest),
+            Some((date(2026, 1, 4), date(2026, 1, 6), 3))
+        );
+        // across a year boundary
+        let summary = summarize_streaks(&[date(2025, 12, 31), date(2026, 1, 1)], date(2026, 1, 1));
+        assert_eq!(
+            streak(&summary.current),
+            Some((date(2025, 12, 31), date(2026, 1, 1), 2))
+        );
+    }
+
+    #[test]
+    fn current_streak_survives_until_today_ends() {
+        let days = [date(2026, 1, 4), date(2026, 1, 5)];
+        // today's entry is still missing
+        let summary = summarize_streaks(&days, date(2026, 1, 6));
+        assert_eq!(
+            streak(&summary.current),
+            Some((date(2026, 1, 4), date(2026, 1, 5), 2))
+        );
+        // yesterday went by without one
+        let summary = summarize_streaks(&days, date(2026, 1, 7));
+        assert_eq!(streak(&summary.current), None);
+        assert_eq!(
+            streak(&summary.longest),
+            Some((date(2026, 1, 4), date(2026, 1, 5), 2))
+        );
+    }
+
+    #[test]
+    fn longest_streak_prefers_the_latest() {
+        let days = [
+            date(2026, 1, 1),
+            date(2026, 1, 2),
+            date(2026, 1, 4),
+            date(2026, 1, 5),
+            date(2026, 1, 8),
+        ];
+        let summary = summarize_streaks(&days, date(2026, 1, 8));
+        assert_eq!(
+            streak(&summary.longest),
+            Some((date(2026, 1, 4), date(2026, 1, 5), 2))
+        );
+        assert_eq!(
+            streak(&summary.current),
+            Some((date(2026, 1, 8), date(2026, 1, 8), 1))
+        );
+    }
+
+    #[test]
+    fn empty_history_has_no_streaks_or_months() {
+        let summary = summarize_streaks(&[], date(2026, 1, 1));
+        assert!(summary.current.is_none());
+        assert!(summary.longest.is_none());
+        assert!(monthly_consistency(&[], date(2026, 1, 1)).is_empty());
+    }
+
+    fn months(days: &[NaiveDate], today: NaiveDate) -> Vec<(NaiveDate, i64, i64)> {
+        monthly_consistency(days, today)
+            .into_iter()
+            .map(|month| (month.month, month.days_logged, month.days))
+            .collect()
+    }
+
+    #[test]
+    fn months_have_their_own_length() {
+        let days = [
+            date(2023, 12, 31),
+            date(2024, 1, 15),
+            date(2024, 2, 28),
+            date(2024, 2, 29),
+            date(2024, 3, 1),
+            date(2024, 3, 10),
+        ];
+        assert_eq!(
+            months(&days, date(2024, 3, 10)),
+            vec![
+                (date(2023, 12, 1), 1, 31),
+                (date(2024, 1, 1), 1, 31),
+                // a leap year
+                (date(2024, 2, 1), 2, 29),
+                // only the days up to today
+                (date(2024, 3, 1), 2, 10),
+            ]
+        );
+        assert_eq!(
+            months(&[date(2023, 2, 1)], date(2023, 3, 1)),
+            vec![(date(2023, 2, 1), 1, 28), (date(2023, 3, 1), 0, 1)]
+        );
+        let consistency = monthly_consistency(&[date(2023, 2, 1)], date(2023, 2, 4));
+        assert_eq!(consistency[0].share, 0.25);
+    }
+
+    fn mood_stats(
+        test_user: &TestUser,
+        bucket: Bucket,
+        tz: &str,
+        pool: &web::Data<Pool>,
+    ) -> Vec<(NaiveDate, i64, f64)> {
+        let stats_query = MoodStatsQuery {
+            bucket: Some(bucket),
+            from: None,
+            to: None,
+            tz: Some(tz.to_string()),
+        };
+        get_mood_stats_query(test_user.logged_user(), stats_query, pool.clone())
+            .unwrap()
+            .into_iter()
+            .map(|bucket| (bucket.start, bucket.count, bucket.mean))
+            .collect()
+    }
+
+    #[test]
+    fn rejects_unknown_timezones() {
+        let pool = pool();
+        let conn = &pool.get().unwrap();
+        assert_eq!(check_timezone(None, conn).unwrap(), "UTC");
+        assert_eq!(
+            check_timezone(Some("Europe/Berlin".to_string()), conn).unwrap(),
+            "Europe/Berlin"
+        );
+        assert!(matches!(
+            check_timezone(Some("Mars/Olympus_Mons".to_string()), conn),
+            Err(ServiceError::BadRequest(_))
+        ));
+        // not a name that could be spliced into the query either
+        assert!(matches!(
+            check_timezone(Some("UTC' --".to_string()), conn),
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+
+    // Berlin switched to summer time on sunday 2026-03-29 at 01:00 UTC
+    #[test]
+    fn buckets_follow_the_local_calendar() {
+        let pool = web::Data::new(pool());
+        let test_user = TestUser::create(&pool);
+        let user_id = test_user.user.id;
+        let conn = &pool.get().unwrap();
+        let low = create_mood(user_id, 1, conn);
+        let high = create_mood(user_id, 5, conn);
+        // 00:30 on sunday in Berlin, still saturday in UTC
+        create_entry_at(user_id, &low, utc(2026, 3, 28, 23, 30), &[], conn);
+        // 23:30 on sunday in Berlin, already in summer time
+        create_entry_at(user_id, &high, utc(2026, 3, 29, 21, 30), &[], conn);
+        // 00:30 on monday in Berlin, the same sunday in UTC
+        create_entry_at(user_id, &high, utc(2026, 3, 29, 22, 30), &[], conn);
+        // 00:30 on the first of april in Berlin
+        create_entry_at(user_id, &low, utc(2026, 3, 31, 22, 30), &[], conn);
+
+        assert_eq!(
+            mood_stats(&test_user, Bucket::Day, "Europe/Berlin", &pool),
+            vec![
+                (date(2026, 3, 29), 2, 3.0),
+                (date(2026, 3, 30), 1, 5.0),
+                (date(2026, 4, 1), 1, 1.0),
+            ]
+        );
+        assert_eq!(
+            mood_stats(&test_user, Bucket::Day, "UTC", &pool),
+            vec![
+                (date(2026, 3, 28), 1, 1.0),
+                (date(2026, 3, 29), 2, 5.0),
+                (date(2026, 3, 31), 1, 1.0),
+            ]
+        );
+        // weeks start on monday
+        assert_eq!(
+            mood_stats(&test_user, Bucket::Week, "Europe/Berlin", &pool),
+            vec![(date(2026, 3, 23), 2, 3.0), (date(2026, 3, 30), 2, 3.0)]
+        );
+        assert_eq!(
+            mood_stats(&test_user, Bucket::Week, "UTC", &pool),
+            vec![
+                (date(2026, 3, 23), 3, 11.0 / 3.0),
+                (date(2026, 3, 30), 1, 1.0)
+            ]
+        );
+        assert_eq!(
+            mood_stats(&test_user, Bucket::Month, "Europe/Berlin", &pool),
+            vec![
+                (date(2026, 3, 1), 3, 11.0 / 3.0),
+                (date(2026, 4, 1), 1, 1.0)
+            ]
+        );
+        assert_eq!(
+            mood_stats(&test_user, Bucket::Month, "UTC", &pool),
+            vec![(date(2026, 3, 1), 4, 3.0)]
+        );
+
+        // the bounds are local days as well
+        let stats_query = MoodStatsQuery {
+            bucket: Some(Bucket::Day),
+            from: Some(date(2026, 3, 30)),
+            to: Some(date(2026, 3, 31)),
+            tz: Some("Europe/Berlin".to_string()),
+        };
+        let buckets = get_mood_stats_query(test_user.logged_user(), stats_query, pool).unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].start, date(2026, 3, 30));
+    }
+
+    fn group(group: &MoodGroup) -> (i64, Option<f64>) {
+        (group.count, group.mean)
+    }
+
+    #[test]
+    fn activity_impact_counts_entries_and_days() {
+        let pool = web::Data::new(pool());
+        let test_user = TestUser::create(&pool);
+        let user_id = test_user.user.id;
+        let conn = &pool.get().unwrap();
+        let moods: Vec<Mood> = (1..=5)
+            .map(|value| create_mood(user_id, value, conn))
+            .collect();
+        let walk = create_activity(user_id, "Walk", conn);
+        let read = create_activity(user_id, "Read", conn);
+        let entries = [
+            (utc(2026, 1, 1, 9, 0), 4, vec![&walk, &read]),
+            (utc(2026, 1, 1, 18, 0), 2, vec![&walk]),
+            (utc(2026, 1, 2, 12, 0), 1, vec![]),
+            (utc(2026, 1, 3, 12, 0), 5, vec![&walk]),
+            (utc(2026, 1, 4, 12, 0), 3, vec![]),
+        ];
+        for (created_at, value, activity_vec) in entries.iter() {
+            let mood = &moods[*value as usize - 1];
+            create_entry_at(user_id, mood, *created_at, activity_vec, conn);
+        }
+
+        let impacts = get_activity_impact_query(
+            test_user.logged_user(),
+            ImpactQuery {
+                tz: Some("UTC".to_string()),
+            },
+            pool,
+        )
+        .unwrap();
+        let walk_impact = impacts.iter().find(|i| i.activity.id == walk.id).unwrap();
+        let read_impact = impacts.iter().find(|i| i.activity.id == read.id).unwrap();
+
+        // entries with a walk: 4, 2 and 5, without: 1 and 3
+        assert_eq!(group(&walk_impact.same_day.with), (3, Some(11.0 / 3.0)));
+        assert_eq!(group(&walk_impact.same_day.without), (2, Some(2.0)));
+        assert_eq!(walk_impact.same_day.difference, Some(11.0 / 3.0 - 2.0));
+        assert_eq!(group(&read_impact.same_day.with), (1, Some(4.0)));
+        assert_eq!(group(&read_impact.same_day.without), (4, Some(11.0 / 4.0)));
+        // a single value has no variance, so there is no interval yet
+        assert_eq!(read_impact.same_day.ci_low, None);
+        assert!(!read_impact.same_day.significant);
+
+        // the days after walks (1st and 3rd) had moods 1 and 3, the one after the 2nd had 5;
+        // the 4th is not followed by a logged day
+        assert_eq!(group(&walk_impact.next_day.with), (2, Some(2.0)));
+        assert_eq!(group(&walk_impact.next_day.without), (1, Some(5.0)));
+        assert_eq!(group(&read_impact.next_day.with), (1, Some(1.0)));
+        assert_eq!(group(&read_impact.next_day.without), (2, Some(4.0)));
+        // walks come first, their days are the better ones
+        assert_eq!(impacts[0].activity.id, walk.id);
+    }
+}
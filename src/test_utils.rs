@@ -0,0 +1,220 @@
+// helpers for tests that need the database; like the server they read DATABASE_URL,
+// which has to point at a migrated database
+use std::{cell::Cell, time::SystemTime};
+
+use diesel::{
+    connection::{AnsiTransactionManager, SimpleConnection},
+    deserialize::{Queryable, QueryableByName},
+    pg::Pg,
+    prelude::*,
+    query_builder::{AsQuery, QueryFragment, QueryId},
+    r2d2::{self, ConnectionManager},
+    sql_types::HasSqlType,
+};
+
+use crate::{
+    auth_handler::LoggedUser,
+    models::{
+        Activity, Entry, Mood, NewActivity, NewEntry, NewEntryActivity, NewMood, NewUser, Pool,
+        User, ENTRY_COLUMNS,
+    },
+    utils::{hash_password, new_token},
+};
+
+pub const PASSWORD: &str = "correct horse battery";
+
+fn database_url() -> String {
+    dotenv::dotenv().ok();
+    std::env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+}
+
+pub fn pool() -> Pool {
+    r2d2::Pool::builder()
+        .max_size(2)
+        .build(ConnectionManager::<PgConnection>::new(database_url()))
+        .expect("Failed to create pool.")
+}
+
+// a verified account with an address of its own, deleted again together with everything
+// it owns once dropped, also when the test fails
+pub struct TestUser {
+    pub user: User,
+    pool: Pool,
+}
+
+impl TestUser {
+    pub fn create(pool: &Pool) -> Self {
+        use crate::schema::users::dsl::{users, verified_at};
+
+        let conn = &pool.get().unwrap();
+        let new_user = NewUser::from_details(
+            format!("test-{}@example.com", &new_token()[..16]),
+            hash_password(PASSWORD).unwrap(),
+        );
+        let user = diesel::insert_into(users)
+            .values(&new_user)
+            .get_result::<User>(conn)
+            .unwrap();
+        let user = diesel::update(&user)
+            .set(verified_at.eq(SystemTime::now()))
+            .get_result::<User>(conn)
+            .unwrap();
+        TestUser {
+            user,
+            pool: pool.clone(),
+        }
+    }
+
+    pub fn logged_user(&self) -> LoggedUser {
+        LoggedUser {
+            id: self.user.id,
+            email: self.user.email.clone(),
+            verified: true,
+            two_factor_enabled: false,
+        }
+    }
+}
+
+impl Drop for TestUser {
+    fn drop(&mut self) {
+        if let Ok(conn) = self.pool.get() {
+            diesel::delete(&self.user).execute(&conn).ok();
+        }
+    }
+}
+
+pub fn create_mood(user_id: i32, value: i32, conn: &PgConnection) -> Mood {
+    use crate::schema::moods::dsl::moods;
+
+    diesel::insert_into(moods)
+        .values(NewMood {
+            user_id,
+            name: format!("Mood {}", value),
+            value,
+            icon: "mood".to_string(),
+        })
+        .get_result::<Mood>(conn)
+        .unwrap()
+}
+
+pub fn create_activity(user_id: i32, name: &str, conn: &PgConnection) -> Activity {
+    use crate::schema::activities::dsl::activities;
+
+    diesel::insert_into(activities)
+        .values(NewActivity {
+            user_id,
+            name,
+            icon: "activity".to_string(),
+        })
+        .get_result::<Activity>(conn)
+        .unwrap()
+}
+
+pub fn create_entry_at(
+    user_id: i32,
+    mood: &Mood,
+    created_at: SystemTime,
+    activity_vec: &[&Activity],
+    conn: &PgConnection,
+) -> Entry {
+    use crate::schema::{entry_activities::dsl::entry_activities, entrys::dsl::entrys};
+
+    let entry = diesel::insert_into(entrys)
+        .values(NewEntry {
+            user_id,
+            mood_id: mood.id,
+            desc: None,
+            created_at: Some(created_at),
+        })
+        .returning(ENTRY_COLUMNS)
+        .get_result::<Entry>(conn)
+        .unwrap();
+    let link_vec: Vec<NewEntryActivity> = activity_vec
+        .iter()
+        .map(|activity| NewEntryActivity {
+            entry_id: entry.id,
+            activity_id: activity.id,
+        })
+        .collect();
+    diesel::insert_into(entry_activities)
+        .values(link_vec)
+        .execute(conn)
+        .unwrap();
+    entry
+}
+
+// a Postgres connection that counts the statements sent through it
+pub struct CountingConnection {
+    conn: PgConnection,
+    queries: Cell<usize>,
+}
+
+impl CountingConnection {
+    pub fn queries(&self) -> usize {
+        self.queries.get()
+    }
+
+    fn count(&self) {
+        self.queries.set(self.queries.get() + 1);
+    }
+}
+
+impl SimpleConnection for CountingConnection {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        self.count();
+        self.conn.batch_execute(query)
+    }
+}
+
+impl Connection for CountingConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        Ok(CountingConnection {
+            conn: PgConnection::establish(database_url)?,
+            queries: Cell::new(0),
+        })
+    }
+
+    fn execute(&self, query: &str) -> QueryResult<usize> {
+        self.count();
+        self.conn.execute(query)
+    }
+
+    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    where
+        T: AsQuery,
+        T::Query: QueryFragment<Pg> + QueryId,
+        Pg: HasSqlType<T::SqlType>,
+        U: Queryable<T::SqlType, Pg>,
+    {
+        self.count();
+        self.conn.query_by_index(source)
+    }
+
+    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    where
+        T: QueryFragment<Pg> + QueryId,
+        U: QueryableByName<Pg>,
+    {
+        self.count();
+        self.conn.query_by_name(source)
+    }
+
+    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Pg> + QueryId,
+    {
+        self.count();
+        self.conn.execute_returning_count(source)
+    }
+
+    fn transaction_manager(&self) -> &AnsiTransactionManager {
+        self.conn.transaction_manager()
+    }
+}
+
+pub fn counting_connection() -> CountingConnection {
+    CountingConnection::establish(&database_url()).expect("Failed to connect to the database.")
+}
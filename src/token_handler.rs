@@ -0,0 +1,173 @@
+use std::time::SystemTime;
+
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth_handler::{LoggedUser, Scope},
+    errors::ServiceError,
+    models::{ApiToken, NewApiToken, Pool, User},
+    utils::{hash_token, new_token},
+};
+
+// makes tokens easy to recognise, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "mt_";
+
+// looks up the token from an `Authorization: Bearer` header and marks it as used
+pub fn touch_api_token(token: &str, conn: &PgConnection) -> Result<(ApiToken, User), ServiceError> {
+    use crate::schema::{
+        api_tokens::dsl::{api_tokens, expires_at, last_used_at, token_hash},
+        users::dsl::users,
+    };
+
+    let now = SystemTime::now();
+    let api_token = diesel::update(
+        api_tokens
+            .filter(token_hash.eq(hash_token(token)))
+            .filter(expires_at.is_null().or(expires_at.gt(now))),
+    )
+    .set(last_used_at.eq(now))
+    .get_result::<ApiToken>(conn)
+    .optional()?
+    .ok_or(ServiceError::Unauthorized)?;
+    let user = users.find(api_token.user_id).get_result::<User>(conn)?;
+    Ok((api_token, user))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenData {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub api_token: ApiToken,
+    // only ever shown in this response
+    pub token: String,
+}
+
+// tokens are managed with the auth cookie only, a token can't create more tokens
+pub async fn create_token(
+    logged_user: LoggedUser,
+    token_data: web::Json<TokenData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    info!("Request to create an api token by {}", logged_user.email);
+    let token_data = token_data.into_inner();
+    let res = web::block(move || create_token_query(logged_user, token_data, pool)).await;
+
+    match res {
+        Ok(created) => Ok(HttpResponse::Ok().json(&created)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn create_token_query(
+    logged_user: LoggedUser,
+    token_data: TokenData,
+    pool: web::Data<Pool>,
+) -> Result<CreatedToken, ServiceError> {
+    use crate::schema::api_tokens::dsl::api_tokens;
+
+    if token_data.name.trim().is_empty() {
+        return Err(ServiceError::BadRequest("A token needs a name".to_string()));
+    }
+    if token_data.scopes.is_empty() {
+        return Err(ServiceError::BadRequest(
+            "A token needs at least one scope".to_string(),
+        ));
+    }
+    if matches!(token_data.expires_at, Some(expiry) if expiry <= SystemTime::now()) {
+        return Err(ServiceError::BadRequest(
+            "Expiry must be in the future".to_string(),
+        ));
+    }
+
+    let conn = &pool.get().unwrap();
+    let token = format!("{}{}", TOKEN_PREFIX, new_token());
+    let scopes: Vec<&str> = Scope::ALL
+        .iter()
+        .filter(|scope| token_data.scopes.contains(scope))
+        .map(|scope| scope.as_str())
+        .collect();
+    let api_token = diesel::insert_into(api_tokens)
+        .values(NewApiToken {
+            user_id: logged_user.id,
+            name: token_data.name.trim(),
+            token_hash: &hash_token(&token),
+            scopes,
+            expires_at: token_data.expires_at,
+        })
+        .get_result::<ApiToken>(conn)?;
+    Ok(CreatedToken { api_token, token })
+}
+
+pub async fn get_tokens(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let res = web::block(move || get_tokens_query(logged_user, pool)).await;
+
+    match res {
+        Ok(token_vec) => Ok(HttpResponse::Ok().json(&token_vec)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn get_tokens_query(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<Vec<ApiToken>, ServiceError> {
+    use crate::schema::api_tokens::dsl::{api_tokens, id, user_id};
+
+    let conn = &pool.get().unwrap();
+    let token_vec = api_tokens
+        .filter(user_id.eq(logged_user.id))
+        .order(id)
+        .get_results::<ApiToken>(conn)?;
+    Ok(token_vec)
+}
+
+pub async fn delete_token(
+    logged_user: LoggedUser,
+    token_id: web::Path<i32>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let token_id = token_id.into_inner();
+    let res = web::block(move || delete_token_query(token_id, logged_user, pool)).await;
+
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn delete_token_query(
+    token_id: i32,
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::api_tokens::dsl::{api_tokens, user_id};
+
+    let conn = &pool.get().unwrap();
+    let deleted = diesel::delete(api_tokens.find(token_id).filter(user_id.eq(logged_user.id)))
+        .execute(conn)?;
+    if deleted == 0 {
+        return Err(ServiceError::NotFound);
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+
+// RFC 6238 with the parameters every authenticator app supports:
+// HMAC-SHA1, 6 digits and 30 second steps
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LENGTH: usize = 20;
+// accept the previous and the next code too, for clocks that drift a little
+const ALLOWED_DRIFT: u64 = 1;
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn new_secret() -> String {
+    let secret: [u8; SECRET_LENGTH] = rand::random();
+    base32::encode(ALPHABET, &secret)
+}
+
+// the `otpauth://` URI authenticator apps read from a QR code
+pub fn provisioning_uri(secret: &str, issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        secret,
+        percent_encode(issuer),
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'@' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn time_step(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() / STEP_SECONDS)
+        .unwrap_or(0)
+}
+
+fn code_for_step(key: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+// the time is passed in so the check can be run against a fixed clock;
+// returns the step the code belongs to, callers reject steps that were already used
+pub fn verify(secret: &str, code: &str, now: SystemTime) -> Option<u64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32::decode(ALPHABET, secret)?;
+    let current = time_step(now);
+    (current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT)
+        .find(|step| code_for_step(&key, *step) == code)
+}
+
+// what an authenticator app would show at `now`
+#[cfg(test)]
+pub fn code_at(secret: &str, now: SystemTime) -> String {
+    let key = base32::decode(ALPHABET, secret).expect("a base32 secret");
+    format!("{:06}", code_for_step(&key, time_step(now)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // base32 of "12345678901234567890", the key of the RFC 6238 SHA-1 test vectors
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn code_for(step: u64) -> String {
+        code_at(RFC_SECRET, at(step * STEP_SECONDS))
+    }
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // the RFC lists 8 digits, ours are the last 6 of them
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in vectors.iter() {
+            assert_eq!(code_at(RFC_SECRET, at(*time)), *code);
+            assert_eq!(
+                verify(RFC_SECRET, code, at(*time)),
+                Some(time / STEP_SECONDS)
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        let now = at(1111111109);
+        let step = time_step(now);
+        assert_eq!(verify(RFC_SECRET, &code_for(step - 1), now), Some(step - 1));
+        assert_eq!(verify(RFC_SECRET, &code_for(step + 1), now), Some(step + 1));
+        assert_eq!(verify(RFC_SECRET, &code_for(step - 2), now), None);
+        assert_eq!(verify(RFC_SECRET, &code_for(step + 2), now), None);
+    }
+
+    #[test]
+    fn window_moves_on_the_step_boundary() {
+        let step = time_step(at(1111111109));
+        let last_second = at(step * STEP_SECONDS + STEP_SECONDS - 1);
+        let next_step = at((step + 1) * STEP_SECONDS);
+        assert!(verify(RFC_SECRET, &code_for(step - 1), last_second).is_some());
+        assert!(verify(RFC_SECRET, &code_for(step + 2), last_second).is_none());
+        assert!(verify(RFC_SECRET, &code_for(step - 1), next_step).is_none());
+        assert!(verify(RFC_SECRET, &code_for(step + 2), next_step).is_some());
+    }
+
+    #[test]
+    fn first_step_has_no_previous_one() {
+        assert_eq!(verify(RFC_SECRET, &code_for(0), at(0)), Some(0));
+        assert_eq!(verify(RFC_SECRET, &code_for(1), at(0)), Some(1));
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let now = at(59);
+        assert_eq!(verify(RFC_SECRET, "287 082", now), Some(1));
+        for code in ["28708", "2870820", "28708a", ""].iter() {
+            assert_eq!(verify(RFC_SECRET, code, now), None);
+        }
+        assert_eq!(verify("not base32!", "287082", now), None);
+    }
+}
@@ -0,0 +1,425 @@
+use std::time::SystemTime;
+
+use actix_identity::Identity;
+use actix_web::{error::BlockingError, web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth_handler::LoggedUser,
+    errors::ServiceError,
+    lockout_handler::{
+        check_guarded, check_ip, record_attempt, register_success, AccountCheck, LoginMethod,
+        LoginOutcome,
+    },
+    mailer::Mailer,
+    models::{NewRecoveryCode, Pool, RecoveryCode, SlimUser, User},
+    session_handler::{complete_two_factor, find_pending_session, ClientInfo},
+    totp,
+    utils::hash_token,
+};
+
+// shown as the account's label in authenticator apps
+const ISSUER: &str = "Moodtracker";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetup {
+    pub secret: String,
+    // render this as a QR code for the authenticator app to scan
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeData {
+    // a code from the authenticator app or one of the recovery codes
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    // only ever shown in this response
+    pub recovery_codes: Vec<String>,
+}
+
+pub fn invalid_code() -> ServiceError {
+    ServiceError::Forbidden("Invalid two-factor code".to_string())
+}
+
+// recovery codes are matched without dashes, spaces or case
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// replaces all recovery codes of the user, returns the new ones
+fn new_recovery_codes(user: &User, conn: &PgConnection) -> Result<Vec<String>, ServiceError> {
+    use crate::schema::recovery_codes::dsl::recovery_codes;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = hex::encode(rand::random::<[u8; 5]>());
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    diesel::delete(RecoveryCode::belonging_to(user)).execute(conn)?;
+    diesel::insert_into(recovery_codes)
+        .values(
+            codes
+                .iter()
+                .map(|code| NewRecoveryCode {
+                    user_id: user.id,
+                    code_hash: hash_token(&normalize_recovery_code(code)),
+                })
+                .collect::<Vec<NewRecoveryCode>>(),
+        )
+        .execute(conn)?;
+    Ok(codes)
+}
+
+// accepts a current TOTP code or an unused recovery code, either only once;
+// the time is passed in for the same reason as in `totp::verify`
+pub fn second_factor_matches(
+    user: &User,
+    code: &str,
+    now: SystemTime,
+    conn: &PgConnection,
+) -> Result<bool, ServiceError> {
+    use crate::schema::{
+        recovery_codes::dsl::{code_hash, used_at},
+        users::dsl::{totp_last_step, users},
+    };
+
+    let secret = match user.totp_secret.as_deref() {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+    if let Some(step) = totp::verify(secret, code, now) {
+        let step = step as i64;
+        let accepted = diesel::update(
+            users
+                .find(user.id)
+                .filter(totp_last_step.is_null().or(totp_last_step.lt(step))),
+        )
+        .set(totp_last_step.eq(step))
+        .execute(conn)?;
+        return Ok(accepted == 1);
+    }
+
+    let used = diesel::update(
+        RecoveryCode::belonging_to(user)
+            .filter(code_hash.eq(hash_token(&normalize_recovery_code(code))))
+            .filter(used_at.is_null()),
+    )
+    .set(used_at.eq(now))
+    .execute(conn)?;
+    if used == 1 {
+        info!("Recovery code used by {}", user.email);
+    }
+    Ok(used == 1)
+}
+
+pub fn check_second_factor(
+    user: &User,
+    code: &str,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    if !second_factor_matches(user, code, SystemTime::now(), conn)? {
+        return Err(invalid_code());
+    }
+    Ok(())
+}
+
+fn enabled_user(logged_user: &LoggedUser, conn: &PgConnection) -> Result<User, ServiceError> {
+    use crate::schema::users::dsl::users;
+
+    let user = users.find(logged_user.id).get_result::<User>(conn)?;
+    if user.totp_enabled_at.is_none() {
+        return Err(ServiceError::BadRequest(
+            "Two-factor authentication is not enabled".to_string(),
+        ));
+    }
+    Ok(user)
+}
+
+// starts the enrollment, 2FA is only required once the first code was confirmed
+pub async fn begin_totp(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    info!(
+        "Request to set up two-factor authentication by {}",
+        logged_user.email
+    );
+    let res = web::block(move || begin_totp_query(logged_user, pool)).await;
+
+    match res {
+        Ok(setup) => Ok(HttpResponse::Ok().json(&setup)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn begin_totp_query(
+    logged_user: LoggedUser,
+    pool: web::Data<Pool>,
+) -> Result<TotpSetup, ServiceError> {
+    use crate::schema::users::dsl::{totp_enabled_at, totp_secret, users};
+
+    let conn = &pool.get().unwrap();
+    let secret = totp::new_secret();
+    let updated = diesel::update(users.find(logged_user.id).filter(totp_enabled_at.is_null()))
+        .set(totp_secret.eq(&secret))
+        .execute(conn)?;
+    if updated == 0 {
+        return Err(ServiceError::Conflict(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+    Ok(TotpSetup {
+        provisioning_uri: totp::provisioning_uri(&secret, ISSUER, &logged_user.email),
+        secret,
+    })
+}
+
+pub async fn confirm_totp(
+    logged_user: LoggedUser,
+    code_data: web::Json<CodeData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let code_data = code_data.into_inner();
+    let res = web::block(move || confirm_totp_query(logged_user, code_data, pool)).await;
+
+    match res {
+        Ok(codes) => Ok(HttpResponse::Ok().json(&codes)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn confirm_totp_query(
+    logged_user: LoggedUser,
+    code_data: CodeData,
+    pool: web::Data<Pool>,
+) -> Result<RecoveryCodes, ServiceError> {
+    use crate::schema::users::dsl::{totp_enabled_at, totp_last_step, users};
+
+    let conn = &pool.get().unwrap();
+    let user = users.find(logged_user.id).get_result::<User>(conn)?;
+    if user.totp_enabled_at.is_some() {
+        return Err(ServiceError::Conflict(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+    let secret = user
+        .totp_secret
+        .as_deref()
+        .ok_or_else(|| ServiceError::BadRequest("Start the two-factor setup first".to_string()))?;
+    let step = totp::verify(secret, &code_data.code, SystemTime::now()).ok_or_else(invalid_code)?;
+    conn.transaction(|| {
+        diesel::update(&user)
+            .set((
+                totp_enabled_at.eq(SystemTime::now()),
+                totp_last_step.eq(step as i64),
+            ))
+            .execute(conn)?;
+        Ok(RecoveryCodes {
+            recovery_codes: new_recovery_codes(&user, conn)?,
+        })
+    })
+}
+
+pub async fn disable_totp(
+    logged_user: LoggedUser,
+    code_data: web::Json<CodeData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    info!(
+        "Request to disable two-factor authentication by {}",
+        logged_user.email
+    );
+    let code_data = code_data.into_inner();
+    let res = web::block(move || disable_totp_query(logged_user, code_data, pool)).await;
+
+    match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn disable_totp_query(
+    logged_user: LoggedUser,
+    code_data: CodeData,
+    pool: web::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::users::dsl::{totp_enabled_at, totp_last_step, totp_secret};
+
+    let conn = &pool.get().unwrap();
+    let user = enabled_user(&logged_user, conn)?;
+    conn.transaction(|| {
+        check_second_factor(&user, &code_data.code, conn)?;
+        diesel::update(&user)
+            .set((
+                totp_secret.eq(None::<String>),
+                totp_enabled_at.eq(None::<SystemTime>),
+                totp_last_step.eq(None::<i64>),
+            ))
+            .execute(conn)?;
+        diesel::delete(RecoveryCode::belonging_to(&user)).execute(conn)?;
+        Ok(())
+    })
+}
+
+pub async fn regenerate_recovery_codes(
+    logged_user: LoggedUser,
+    code_data: web::Json<CodeData>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let code_data = code_data.into_inner();
+    let res =
+        web::block(move || regenerate_recovery_codes_query(logged_user, code_data, pool)).await;
+
+    match res {
+        Ok(codes) => Ok(HttpResponse::Ok().json(&codes)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn regenerate_recovery_codes_query(
+    logged_user: LoggedUser,
+    code_data: CodeData,
+    pool: web::Data<Pool>,
+) -> Result<RecoveryCodes, ServiceError> {
+    let conn = &pool.get().unwrap();
+    let user = enabled_user(&logged_user, conn)?;
+    conn.transaction(|| {
+        check_second_factor(&user, &code_data.code, conn)?;
+        Ok(RecoveryCodes {
+            recovery_codes: new_recovery_codes(&user, conn)?,
+        })
+    })
+}
+
+// second step of the login, for the session the password login left pending
+pub async fn verify_login(
+    req: HttpRequest,
+    id: Identity,
+    code_data: web::Json<CodeData>,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<HttpResponse, ServiceError> {
+    let token = id.identity().ok_or(ServiceError::Unauthorized)?;
+    let client = ClientInfo::from_request(&req);
+    let code_data = code_data.into_inner();
+    let res = web::block(move || verify_login_query(token, code_data, client, pool, mailer)).await;
+
+    match res {
+        Ok(user) => Ok(HttpResponse::Ok().json(&user)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    }
+}
+
+fn verify_login_query(
+    token: String,
+    code_data: CodeData,
+    client: ClientInfo,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<SlimUser, ServiceError> {
+    use crate::schema::users::dsl::users;
+
+    let conn = &pool.get().unwrap();
+    let session = find_pending_session(&token, conn)?;
+    let user = users.find(session.user_id).get_result::<User>(conn)?;
+    let record = |outcome: LoginOutcome| {
+        record_attempt(
+            Some(user.id),
+            &user.email,
+            LoginMethod::TwoFactor,
+            outcome,
+            &client,
+            conn,
+        )
+    };
+
+    // codes are guessed just like passwords, so they count against the same limits
+    if let Err(err) = check_ip(&client, conn) {
+        record(LoginOutcome::Throttled)?;
+        return Err(err);
+    }
+    let user = match check_guarded(user.id, &**mailer, conn, |user| {
+        let matched = second_factor_matches(user, &code_data.code, SystemTime::now(), conn)?;
+        if matched {
+            complete_two_factor(&session, conn)?;
+        }
+        Ok(matched)
+    })? {
+        AccountCheck::Passed(user) => user,
+        // the password was right, so there is nothing left to hide about the account
+        AccountCheck::Locked(err) => {
+            record(LoginOutcome::Locked)?;
+            return Err(err);
+        }
+        AccountCheck::Failed => {
+            record(LoginOutcome::InvalidCode)?;
+            return Err(invalid_code());
+        }
+    };
+    record(LoginOutcome::Success)?;
+    register_success(&user, conn)?;
+    Ok(user.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::test_utils::{pool, TestUser};
+
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn totp_codes_are_accepted_once() {
+        use crate::schema::users::dsl::totp_secret;
+
+        let pool = pool();
+        let test_user = TestUser::create(&pool);
+        let conn = &pool.get().unwrap();
+        let user = diesel::update(&test_user.user)
+            .set(totp_secret.eq(SECRET))
+            .get_result::<User>(conn)
+            .unwrap();
+
+        // 287082 belongs to step 1, which starts at 30
+        assert!(second_factor_matches(&user, "287082", at(59), conn).unwrap());
+        assert!(!second_factor_matches(&user, "287082", at(59), conn).unwrap());
+        // still inside the drift window of step 2, but already used
+        assert!(!second_factor_matches(&user, "287082", at(61), conn).unwrap());
+        // once step 2 was used, step 1 and 0 are done for as well
+        let step_two = totp::code_at(SECRET, at(61));
+        assert!(second_factor_matches(&user, &step_two, at(61), conn).unwrap());
+        let step_zero = totp::code_at(SECRET, at(0));
+        assert!(!second_factor_matches(&user, &step_zero, at(31), conn).unwrap());
+    }
+}
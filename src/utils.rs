@@ -1,30 +1,114 @@
-use argon2::Config;
+use argon2::{Config, ThreadMode, Variant, Version};
+use log::error;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
 
 use crate::errors::ServiceError;
 
 lazy_static::lazy_static! {
     pub static ref SECRET_KEY: String = std::env::var("SECRET_KEY").unwrap_or_else(|_| "0123".repeat(8));
+    // links in outgoing mail point here
+    pub static ref FRONTEND_URL: String = std::env::var("FRONTEND_URL").expect("FRONTEND_URL must be set");
 }
 
-const SALT: &[u8] = b"supersecuresalt";
+const MIN_PASSWORD_LENGTH: usize = 8;
 
-// WARNING THIS IS ONLY FOR DEMO PLEASE DO MORE RESEARCH FOR PRODUCTION USE
-pub fn hash_password(password: &str) -> Result<String, ServiceError> {
-    let config = Config {
+const SALT_LENGTH: usize = 16;
+
+fn env_u32(name: &str, default: u32) -> u32 {
+    std::env::var(name)
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be a positive number", name))
+        })
+        .unwrap_or(default)
+}
+
+lazy_static::lazy_static! {
+    // defaults follow the OWASP recommendation for Argon2id (19 MiB, 2 iterations, 1 lane)
+    pub static ref ARGON2_CONFIG: Config<'static> = Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: env_u32("ARGON2_MEMORY_KIB", 19 * 1024),
+        time_cost: env_u32("ARGON2_ITERATIONS", 2),
+        lanes: env_u32("ARGON2_PARALLELISM", 1),
+        thread_mode: ThreadMode::Sequential,
         secret: SECRET_KEY.as_bytes(),
-        ..Default::default()
+        ad: &[],
+        hash_length: 32,
     };
-    argon2::hash_encoded(password.as_bytes(), SALT, &config).map_err(|err| {
-        dbg!(err);
+}
+
+// every hash gets its own random salt, it is stored inside the encoded hash
+pub fn hash_password(password: &str) -> Result<String, ServiceError> {
+    let salt: [u8; SALT_LENGTH] = rand::random();
+    argon2::hash_encoded(password.as_bytes(), &salt, &ARGON2_CONFIG).map_err(|err| {
+        error!("Could not hash password: {}", err);
         ServiceError::InternalServerError
     })
 }
 
+// the encoded hash carries its own variant and parameters, so hashes made with older settings
+// (including the shared-salt Argon2i ones) still verify;
+// a wrong password is Ok(false), an error means the stored hash itself is broken
 pub fn verify(hash: &str, password: &str) -> Result<bool, ServiceError> {
     argon2::verify_encoded_ext(hash, password.as_bytes(), SECRET_KEY.as_bytes(), &[]).map_err(
         |err| {
-            dbg!(err);
-            ServiceError::Unauthorized
+            error!("Could not verify password hash: {}", err);
+            ServiceError::InternalServerError
         },
     )
 }
+
+// true when the hash was not made with the current variant and parameters;
+// the legacy shared-salt hashes are all Argon2i, so they are always upgraded
+pub fn needs_rehash(hash: &str) -> bool {
+    let config = &*ARGON2_CONFIG;
+    let prefix = format!(
+        "${}$v={}$m={},t={},p={}$",
+        config.variant.as_lowercase_str(),
+        config.version.as_u32(),
+        config.mem_cost,
+        config.time_cost,
+        config.lanes
+    );
+    !hash.starts_with(&prefix)
+}
+
+pub fn validate_email(email: &str) -> Result<(), ServiceError> {
+    email
+        .parse::<lettre::Address>()
+        .map(|_| ())
+        .map_err(|_| ServiceError::BadRequest(format!("Invalid email address: {}", email)))
+}
+
+pub fn validate_password(password: &str) -> Result<(), ServiceError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(ServiceError::BadRequest(format!(
+            "Passwords need at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// random token handed out once, e.g. in a reset link
+pub fn new_token() -> String {
+    let token: [u8; 32] = rand::random();
+    hex::encode(token)
+}
+
+// tokens are random enough that a plain SHA-256 is sufficient to store them
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// lets an `Option<Option<T>>` field tell a missing key (None) apart from an explicit null (Some(None))
+pub fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}